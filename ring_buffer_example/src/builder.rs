@@ -0,0 +1,167 @@
+#![allow(dead_code, clippy::needless_return)]
+use crate::ring_buffer::{Error, Result, RingBuf, RingMode};
+use crate::storage::{Backing, MapOptions, Placement, Storage};
+use std::io;
+
+/// Builds a `RingBuf` whose memory is placed for latency: mapped with huge
+/// pages, bound to a NUMA node, prefaulted and locked. Every placement request
+/// is best effort unless `strict` is set, so the same code runs on a laptop
+/// without huge pages or `CAP_IPC_LOCK`; `Storage::placement` tells what was
+/// granted.
+#[derive(Debug, Clone)]
+pub struct RingBuilder {
+    /// Ring size in u64 words, as for `RingBuf::new`.
+    count: usize,
+    mode: RingMode,
+    backing: Backing,
+    options: MapOptions,
+    strict: bool,
+}
+
+impl RingBuilder {
+    pub fn new(count: usize) -> Self {
+        return Self {
+            count,
+            mode: RingMode::Blocking,
+            backing: Backing::Heap,
+            options: MapOptions::default(),
+            strict: false,
+        };
+    }
+
+    pub fn mode(mut self, mode: RingMode) -> Self {
+        self.mode = mode;
+        return self;
+    }
+
+    /// Placement options below switch the backing to `Mapped`; choosing
+    /// another backing afterwards drops them.
+    pub fn backing(mut self, backing: Backing) -> Self {
+        self.backing = backing;
+        return self;
+    }
+
+    pub fn huge_pages(mut self, on: bool) -> Self {
+        self.options.huge_pages = on;
+        self.backing = Backing::Mapped;
+        return self;
+    }
+
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.options.numa_node = Some(node);
+        self.backing = Backing::Mapped;
+        return self;
+    }
+
+    pub fn prefault(mut self, on: bool) -> Self {
+        self.options.prefault = on;
+        self.backing = Backing::Mapped;
+        return self;
+    }
+
+    pub fn mlock(mut self, on: bool) -> Self {
+        self.options.mlock = on;
+        self.backing = Backing::Mapped;
+        return self;
+    }
+
+    /// Fail instead of falling back when a placement request is refused.
+    pub fn strict(mut self, on: bool) -> Self {
+        self.strict = on;
+        return self;
+    }
+
+    pub fn build(self) -> Result<RingBuf> {
+        let len = self.count * 8;
+        if !RingBuf::is_power_of_two(len) {
+            return Err(Error::InvalidCapacity { len });
+        }
+        let options = if self.backing == Backing::Mapped {
+            self.options
+        } else {
+            MapOptions::default()
+        };
+        let storage = Storage::with_options(self.count, self.backing, &options)
+            .map_err(|err| Error::from_setup(err, len))?;
+        if self.strict {
+            check_placement(&options, storage.placement())?;
+        }
+        return Ok(RingBuf::from_storage(storage, self.mode));
+    }
+}
+
+fn check_placement(options: &MapOptions, got: Placement) -> Result<()> {
+    let missing = if options.huge_pages && !got.huge_pages {
+        "huge pages"
+    } else if options.numa_node.is_some() && got.numa_node != options.numa_node {
+        "NUMA binding"
+    } else if options.prefault && !got.prefaulted {
+        "prefaulting"
+    } else if options.mlock && !got.locked {
+        "mlock"
+    } else {
+        return Ok(());
+    };
+    return Err(Error::Io(io::Error::other(format!("{} refused", missing))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(mut ring: RingBuf) {
+        let data: Vec<u8> = (0..ring.len()).map(|i| i as u8).collect();
+        assert_eq!(ring.write(&data).unwrap().1, ring.len());
+        let mut out = vec![0u8; ring.len()];
+        assert_eq!(ring.read(&mut out).unwrap().1, ring.len());
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn default_builder_matches_new() {
+        let ring = RingBuilder::new(8).build().unwrap();
+        assert_eq!(ring.len(), 64);
+        assert_eq!(ring.get_raw_buf().0.placement(), Placement::default());
+        round_trip(ring);
+        assert!(matches!(
+            RingBuilder::new(6).build(),
+            Err(Error::InvalidCapacity { len: 48 })
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "mmap placement is not modelled")]
+    fn placement_requests_fall_back_without_privileges() {
+        // 2 MiB, so huge pages are at least attempted
+        let ring = RingBuilder::new(1 << 18)
+            .huge_pages(true)
+            .numa_node(0)
+            .prefault(true)
+            .mlock(true)
+            .build()
+            .unwrap();
+        let placement = ring.get_raw_buf().0.placement();
+        assert!(placement.prefaulted);
+        assert!(placement.numa_node.is_none_or(|node| node == 0));
+        round_trip(ring);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "mmap placement is not modelled")]
+    fn strict_builder_reports_refused_placement() {
+        // no machine this runs on has a node 1000
+        let lenient = RingBuilder::new(512).numa_node(1000).build().unwrap();
+        assert_eq!(lenient.get_raw_buf().0.placement().numa_node, None);
+        round_trip(lenient);
+
+        match RingBuilder::new(512).numa_node(1000).strict(true).build() {
+            Err(Error::Io(err)) => assert_eq!(err.to_string(), "NUMA binding refused"),
+            other => panic!(
+                "expected NUMA binding refused, got {:?}",
+                other.map(|r| r.len())
+            ),
+        }
+        let plain = RingBuilder::new(512).prefault(true).strict(true).build();
+        assert!(plain.is_ok());
+    }
+}
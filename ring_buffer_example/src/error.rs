@@ -0,0 +1,129 @@
+#![allow(dead_code, clippy::needless_return)]
+use crate::ring_buffer::RingMode;
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Asked to consume more bytes than the ring holds.
+    InsufficientData { requested: usize, available: usize },
+    /// Asked to produce or write more bytes than the ring has room for.
+    InsufficientSpace { requested: usize, free: usize },
+    /// Ring size is not usable, e.g. not a power of two, or not page-aligned
+    /// for a mirrored mapping.
+    InvalidCapacity { len: usize },
+    /// A single message needs more bytes than the whole ring holds.
+    TooLarge { len: usize, capacity: usize },
+    /// `op` does not know about the ring's mode, e.g. an iov call on a
+    /// stamped ring, which would read the chunk headers as data.
+    Unsupported { op: &'static str, mode: RingMode },
+    /// A `SlotRing` cannot carve the ring into slots of `slot_size` bytes
+    /// aligned to `align`; `reason` says which requirement failed.
+    InvalidSlots {
+        slot_size: usize,
+        align: usize,
+        reason: &'static str,
+    },
+    /// A `RingSet` already holds `capacity` rings.
+    SetFull { capacity: usize },
+    /// The peer has gone away or this side was shut down.
+    Closed,
+    /// Ring contents failed a consistency check. `offset` is the stream offset
+    /// of the bad record and `expected_seq` the sequence number it should carry.
+    Corrupted { offset: u64, expected_seq: u32 },
+    /// The stamped chunk at `head` does not end at the `expected_tail` its
+    /// header claims, e.g. because it was read before it was fully published.
+    StaleChunk { head: u32, expected_tail: u32 },
+    /// Setting up the ring's memory failed.
+    Io(io::Error),
+    /// A typed message could not be encoded or decoded.
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InsufficientData {
+                requested,
+                available,
+            } => write!(
+                f,
+                "insufficient data: requested {} bytes, {} available",
+                requested, available
+            ),
+            Error::InsufficientSpace { requested, free } => write!(
+                f,
+                "insufficient space: requested {} bytes, {} free",
+                requested, free
+            ),
+            Error::InvalidCapacity { len } => write!(f, "invalid ring capacity of {} bytes", len),
+            Error::TooLarge { len, capacity } => write!(
+                f,
+                "message of {} bytes does not fit a ring of {} bytes",
+                len, capacity
+            ),
+            Error::Unsupported { op, mode } => {
+                write!(f, "{} is not supported on a {:?} ring", op, mode)
+            }
+            Error::InvalidSlots {
+                slot_size,
+                align,
+                reason,
+            } => write!(
+                f,
+                "cannot make {} byte slots aligned to {}: {}",
+                slot_size, align, reason
+            ),
+            Error::SetFull { capacity } => {
+                write!(f, "ring set is full with {} rings", capacity)
+            }
+            Error::Closed => write!(f, "ring is closed"),
+            Error::Corrupted {
+                offset,
+                expected_seq,
+            } => write!(
+                f,
+                "ring contents are corrupted at offset {} (expected record {})",
+                offset, expected_seq
+            ),
+            Error::StaleChunk {
+                head,
+                expected_tail,
+            } => write!(
+                f,
+                "stale chunk at head {} (stamp expects tail {})",
+                head, expected_tail
+            ),
+            Error::Io(err) => write!(f, "ring memory setup failed: {}", err),
+            Error::Codec(err) => write!(f, "message codec failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Codec(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Map a failure to set up `len` bytes of ring storage. The storage layer
+    /// reports sizes it cannot back as `InvalidInput`.
+    pub(crate) fn from_setup(err: io::Error, len: usize) -> Self {
+        if err.kind() == io::ErrorKind::InvalidInput {
+            return Error::InvalidCapacity { len };
+        }
+        return Error::Io(err);
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        return Error::Io(err);
+    }
+}
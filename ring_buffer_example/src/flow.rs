@@ -0,0 +1,237 @@
+//! Credit-based flow control, for producers that should slow down upstream
+//! before the ring fills rather than find out when a write comes back short.
+//!
+//! Free space is treated as credit granted by the consumer. The consumer
+//! grants it in batches simply by releasing lazily: a `Consumer` with
+//! `Batch { bytes: n, .. }` publishes `head` once per `n` bytes read. The
+//! producer spends credits as it writes and only looks at `head` again when it
+//! runs out, so between grants it never touches the consumer's cache line.
+//! Optional watermarks on the bytes outstanding (written, not yet granted back)
+//! call back once on the way up past `high` and once on the way down past `low`.
+#![allow(dead_code, clippy::needless_return)]
+use crate::handle::Producer;
+use crate::ring_buffer::Result;
+
+/// Which watermark the outstanding bytes just crossed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// At or above `high`: time to throttle upstream.
+    High,
+    /// Back at or below `low`: upstream may resume.
+    Low,
+}
+
+struct Watermarks {
+    high: usize,
+    low: usize,
+    on_cross: Box<dyn FnMut(Level) + Send>,
+}
+
+/// A producer handle that writes against credits.
+pub struct CreditProducer {
+    producer: Producer,
+    /// Bytes granted by the consumer and not spent yet.
+    credits: usize,
+    watermarks: Option<Watermarks>,
+    throttled: bool,
+}
+
+impl CreditProducer {
+    pub fn new(producer: Producer) -> Self {
+        let credits = producer.ring().available_space();
+        return Self {
+            producer,
+            credits,
+            watermarks: None,
+            throttled: false,
+        };
+    }
+
+    pub fn producer(&self) -> &Producer {
+        return &self.producer;
+    }
+
+    /// Call `on_cross` when the outstanding bytes reach `high`, and again when
+    /// they fall back to `low`. Requires `low < high <= len()`.
+    pub fn set_watermarks(
+        &mut self,
+        high: usize,
+        low: usize,
+        on_cross: impl FnMut(Level) + Send + 'static,
+    ) {
+        assert!(low < high && high <= self.producer.ring().len());
+        self.watermarks = Some(Watermarks {
+            high,
+            low,
+            on_cross: Box::new(on_cross),
+        });
+        self.throttled = false;
+        self.check();
+    }
+
+    pub fn credits(&self) -> usize {
+        return self.credits;
+    }
+
+    /// Bytes written that the consumer has not granted back yet, as last seen.
+    pub fn outstanding(&self) -> usize {
+        return self.producer.ring().len() - self.credits;
+    }
+
+    /// Whether the high watermark has been crossed and the low one not since.
+    pub fn is_throttled(&self) -> bool {
+        return self.throttled;
+    }
+
+    /// Pick up credits the consumer has granted since the last look. Call it
+    /// while throttled to find out when to resume.
+    pub fn refresh(&mut self) -> usize {
+        // try_write publishes before returning, so nothing is deferred here
+        self.credits = self.producer.ring().available_space();
+        self.check();
+        return self.credits;
+    }
+
+    fn check(&mut self) {
+        let outstanding = self.outstanding();
+        let Some(marks) = &mut self.watermarks else {
+            return;
+        };
+        if !self.throttled && outstanding >= marks.high {
+            self.throttled = true;
+            (marks.on_cross)(Level::High);
+        } else if self.throttled && outstanding <= marks.low {
+            self.throttled = false;
+            (marks.on_cross)(Level::Low);
+        }
+    }
+
+    /// Write as much of `buf` as the credits allow, refreshing them first if
+    /// they do not cover it. Never blocks.
+    pub fn try_write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.credits < buf.len() || self.throttled {
+            self.refresh();
+        }
+        let n = self.credits.min(buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        // the credits are a lower bound on the free space, so all n bytes go in
+        let (_, written) = self.producer.try_write(&buf[..n])?;
+        self.credits -= written;
+        self.check();
+        return Ok(written);
+    }
+
+    /// Write at least one byte of `buf`, waiting for credits if there are none.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        loop {
+            let n = self.try_write(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.producer.wait_space();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::Batch;
+    use crate::ring_buffer::RingBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn credits_come_back_in_batches() {
+        // 64 byte ring; the consumer grants 16 bytes at a time
+        let (producer, mut consumer) = RingBuf::new(8).split();
+        consumer.set_batch(Batch {
+            bytes: 16,
+            items: usize::MAX,
+        });
+        let mut producer = CreditProducer::new(producer);
+        assert_eq!(producer.credits(), 64);
+
+        assert_eq!(producer.try_write(&[1u8; 40]).unwrap(), 40);
+        assert_eq!((producer.credits(), producer.outstanding()), (24, 40));
+
+        consumer.commit(8).unwrap();
+        assert_eq!(producer.refresh(), 24);
+        consumer.commit(8).unwrap();
+        assert_eq!(producer.refresh(), 40);
+
+        // a write the credits do not cover refreshes them, then stops short
+        consumer.commit(8).unwrap();
+        assert_eq!(producer.try_write(&[2u8; 50]).unwrap(), 40);
+        assert_eq!(producer.credits(), 0);
+        assert_eq!(producer.try_write(&[3u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn watermarks_fire_once_each_way() {
+        let (producer, consumer) = RingBuf::new(8).split();
+        let mut producer = CreditProducer::new(producer);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        producer.set_watermarks(48, 16, move |level| log.lock().unwrap().push(level));
+
+        producer.try_write(&[0u8; 40]).unwrap();
+        producer.try_write(&[0u8; 10]).unwrap();
+        producer.try_write(&[0u8; 4]).unwrap();
+        assert!(producer.is_throttled());
+        assert_eq!(*seen.lock().unwrap(), [Level::High]);
+
+        let mut out = [0u8; 30];
+        consumer.try_read(&mut out).unwrap();
+        producer.refresh();
+        assert!(producer.is_throttled());
+        consumer.try_read(&mut out[..10]).unwrap();
+        producer.refresh();
+        assert!(!producer.is_throttled());
+        assert_eq!(*seen.lock().unwrap(), [Level::High, Level::Low]);
+    }
+
+    #[test]
+    fn throttled_stream_delivers_everything() {
+        let (producer, mut consumer) = RingBuf::new(16).split();
+        consumer.set_batch(Batch {
+            bytes: 32,
+            items: usize::MAX,
+        });
+        let mut producer = CreditProducer::new(producer);
+        let crossings = Arc::new(Mutex::new(0));
+        let count = crossings.clone();
+        producer.set_watermarks(96, 32, move |_| *count.lock().unwrap() += 1);
+
+        let total = 50_000usize;
+        let writer = thread::spawn(move || {
+            let data: Vec<u8> = (0..total).map(|i| i as u8).collect();
+            let mut sent = 0;
+            while sent < total {
+                sent += producer.write(&data[sent..(sent + 40).min(total)]).unwrap();
+            }
+        });
+        let mut got = 0;
+        loop {
+            let (addr, n) = consumer.get_data_buf().unwrap();
+            if n == 0 {
+                if consumer.is_eof() {
+                    break;
+                }
+                consumer.flush();
+                consumer.wait_data();
+                continue;
+            }
+            let n = n.min(24);
+            let bytes = unsafe { std::slice::from_raw_parts(addr as *const u8, n) };
+            assert!(bytes.iter().enumerate().all(|(i, &b)| b == (got + i) as u8));
+            consumer.commit(n).unwrap();
+            got += n;
+        }
+        writer.join().unwrap();
+        assert_eq!(got, total);
+        assert!(*crossings.lock().unwrap() > 0);
+    }
+}
@@ -0,0 +1,313 @@
+//! Messages of any size over a ring of fixed size.
+//!
+//! A message goes out as fragments, each a little-endian `u32` header followed
+//! by its payload. The low 31 bits of the header are the payload length and the
+//! top bit says more fragments follow. A fragment is cut to whatever space is
+//! free when it is written, so the sender never needs room for the whole
+//! message, and the receiver can either stream the payload out as it arrives or
+//! collect it up to a size limit.
+#![allow(dead_code, clippy::needless_return)]
+use crate::handle::{Consumer, Producer};
+use crate::ring_buffer::{Error, Result, Side};
+
+const HEADER: usize = 4;
+const MORE: u32 = 1 << 31;
+const MAX_FRAGMENT: usize = (MORE - 1) as usize;
+
+/// Sends fragmented messages through a producer handle.
+pub struct MessageSender {
+    producer: Producer,
+}
+
+impl MessageSender {
+    pub fn new(producer: Producer) -> Self {
+        return Self { producer };
+    }
+
+    pub fn producer(&self) -> &Producer {
+        return &self.producer;
+    }
+
+    /// Send `msg` as one message, waiting for space as needed.
+    pub fn send(&mut self, msg: &[u8]) -> Result<()> {
+        return self.put(msg, true);
+    }
+
+    /// Start a message whose length is not known up front.
+    pub fn begin(&mut self) -> MessageWriter<'_> {
+        return MessageWriter {
+            sender: self,
+            finished: false,
+        };
+    }
+
+    // write `data` as fragments; the last one closes the message if `last`
+    fn put(&mut self, mut data: &[u8], last: bool) -> Result<()> {
+        loop {
+            self.producer
+                .ring()
+                .wait_until(Side::Producer, |ring| ring.available_space() > HEADER);
+            if self.producer.is_closed() {
+                return Err(Error::Closed);
+            }
+            let room = self.producer.ring().available_space() - HEADER;
+            let n = data.len().min(room).min(MAX_FRAGMENT);
+            let more = n < data.len() || !last;
+            let header = n as u32 | if more { MORE } else { 0 };
+
+            // only this side adds data, so both writes find the space checked above
+            self.producer.try_write(&header.to_le_bytes())?;
+            self.producer.try_write(&data[..n])?;
+            data = &data[n..];
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A message being written in pieces. Each `write` becomes one or more
+/// fragments; `finish`, or dropping the writer, ends the message.
+pub struct MessageWriter<'a> {
+    sender: &'a mut MessageSender,
+    finished: bool,
+}
+
+impl MessageWriter<'_> {
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        return self.sender.put(data, false);
+    }
+
+    /// End the message with an empty final fragment.
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        return self.sender.put(&[], true);
+    }
+}
+
+impl Drop for MessageWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.sender.put(&[], true);
+        }
+    }
+}
+
+/// Receives fragmented messages through a consumer handle.
+pub struct MessageReceiver {
+    consumer: Consumer,
+    /// Payload bytes of the current fragment not yet read.
+    remaining: usize,
+    /// The current fragment is not the message's last.
+    more: bool,
+    /// Part of a message has been read and its end has not.
+    open: bool,
+}
+
+impl MessageReceiver {
+    pub fn new(consumer: Consumer) -> Self {
+        return Self {
+            consumer,
+            remaining: 0,
+            more: false,
+            open: false,
+        };
+    }
+
+    pub fn consumer(&self) -> &Consumer {
+        return &self.consumer;
+    }
+
+    /// Copy the next bytes of the current message into `buf`. Returns how many
+    /// were copied and whether that ended the message, or `None` if nothing is
+    /// available yet. A read never spans two messages.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> Result<Option<(usize, bool)>> {
+        while self.remaining == 0 {
+            let mut header = [0u8; HEADER];
+            if self.consumer.peek(&mut header)? < HEADER {
+                return Ok(None);
+            }
+            self.consumer.commit(HEADER)?;
+            let header = u32::from_le_bytes(header);
+            self.remaining = (header & !MORE) as usize;
+            self.more = header & MORE != 0;
+            self.open = true;
+            if self.remaining == 0 && !self.more {
+                self.open = false;
+                return Ok(Some((0, true)));
+            }
+        }
+
+        let want = self.remaining.min(buf.len());
+        let (_, n) = self.consumer.try_read(&mut buf[..want])?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.remaining -= n;
+        let end = self.remaining == 0 && !self.more;
+        if end {
+            self.open = false;
+        }
+        return Ok(Some((n, end)));
+    }
+
+    /// Like `try_read`, but waits for data. `None` at end of stream; fails with
+    /// `Error::Closed` if the sender went away in the middle of a message.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<Option<(usize, bool)>> {
+        loop {
+            if let Some(res) = self.try_read(buf)? {
+                return Ok(Some(res));
+            }
+            if self.consumer.is_eof() {
+                if self.open {
+                    return Err(Error::Closed);
+                }
+                return Ok(None);
+            }
+            // release the headers read so far so the wait sees only new data
+            self.consumer.flush();
+            self.consumer.wait_data();
+        }
+    }
+
+    /// Collect the next whole message into `out`. Returns false at end of
+    /// stream. A message longer than `max_len` is read to its end and dropped,
+    /// and reported as `Error::TooLarge`.
+    pub fn recv(&mut self, out: &mut Vec<u8>, max_len: usize) -> Result<bool> {
+        out.clear();
+        let mut len = 0;
+        let mut discard = Vec::new();
+        loop {
+            let start = out.len();
+            // grow by the fragment in hand, or by a page while waiting for a header
+            let chunk = if self.remaining > 0 {
+                self.remaining
+            } else {
+                4096
+            };
+            let res = if len <= max_len {
+                out.resize(start + chunk.min((max_len - start).saturating_add(1)), 0);
+                self.read(&mut out[start..])
+            } else {
+                discard.resize(chunk.min(4096), 0);
+                self.read(&mut discard)
+            };
+            let Some((n, end)) = res? else {
+                out.clear();
+                return Ok(false);
+            };
+            len += n;
+            if len <= max_len {
+                out.truncate(start + n);
+            } else {
+                out.clear();
+            }
+            if end {
+                if len > max_len {
+                    return Err(Error::TooLarge {
+                        len,
+                        capacity: max_len,
+                    });
+                }
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ring_buffer::RingBuf;
+    use std::thread;
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        return (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect();
+    }
+
+    #[test]
+    fn messages_larger_than_the_ring_are_reassembled() {
+        // 64 byte ring
+        let (producer, consumer) = RingBuf::new(8).split();
+        let mut sender = MessageSender::new(producer);
+        let mut receiver = MessageReceiver::new(consumer);
+        let sizes = [0, 1, 60, 61, 64, 1000, 5000, 3];
+
+        let writer = thread::spawn(move || {
+            for (i, &len) in sizes.iter().enumerate() {
+                sender.send(&pattern(len, i as u8)).unwrap();
+            }
+        });
+        let mut out = Vec::new();
+        for (i, &len) in sizes.iter().enumerate() {
+            assert!(receiver.recv(&mut out, usize::MAX).unwrap());
+            assert_eq!(out, pattern(len, i as u8), "message {}", i);
+        }
+        assert!(!receiver.recv(&mut out, usize::MAX).unwrap());
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn streamed_message_is_read_incrementally() {
+        let (producer, consumer) = RingBuf::new(4).split();
+        let mut sender = MessageSender::new(producer);
+        let mut receiver = MessageReceiver::new(consumer);
+        let body = pattern(700, 9);
+
+        let writer = thread::spawn(move || {
+            let mut msg = sender.begin();
+            for piece in body.chunks(45) {
+                msg.write(piece).unwrap();
+            }
+            msg.finish().unwrap();
+            // dropping an unfinished writer still ends its message
+            sender.begin().write(b"tail").unwrap();
+        });
+
+        let mut got = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let (n, end) = receiver.read(&mut buf).unwrap().unwrap();
+            assert!(n <= 7);
+            got.extend_from_slice(&buf[..n]);
+            if end {
+                break;
+            }
+        }
+        assert_eq!(got, pattern(700, 9));
+
+        let mut out = Vec::new();
+        assert!(receiver.recv(&mut out, 16).unwrap());
+        assert_eq!(out, b"tail");
+        assert_eq!(receiver.read(&mut buf).unwrap(), None);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn over_limit_and_truncated_messages_are_errors() {
+        let (producer, consumer) = RingBuf::new(8).split();
+        let mut sender = MessageSender::new(producer);
+        let mut receiver = MessageReceiver::new(consumer);
+
+        sender.send(&[1u8; 40]).unwrap();
+        sender.send(&[2u8; 10]).unwrap();
+        let mut out = Vec::new();
+        match receiver.recv(&mut out, 32) {
+            Err(Error::TooLarge { len, capacity }) => assert_eq!((len, capacity), (40, 32)),
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+        assert!(receiver.recv(&mut out, 32).unwrap());
+        assert_eq!(out, [2u8; 10]);
+
+        let mut msg = sender.begin();
+        msg.write(&[3u8; 5]).unwrap();
+        std::mem::forget(msg);
+        drop(sender);
+        assert!(matches!(receiver.recv(&mut out, 32), Err(Error::Closed)));
+    }
+}
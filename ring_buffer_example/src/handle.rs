@@ -0,0 +1,648 @@
+#![allow(dead_code, clippy::needless_return)]
+use crate::notify::Notify;
+use crate::ring_buffer::{
+    CONSUMER_CLOSED, Error, PRODUCER_CLOSED, Result, RingBuf, RingMode, STAMP, Side,
+};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering, fence};
+
+/// How many committed bytes or items a handle may hold back before publishing
+/// them. Zero in either field publishes on every commit, which is the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Batch {
+    pub bytes: usize,
+    pub items: usize,
+}
+
+impl Batch {
+    /// Publish once `1/fraction` of a `len` byte ring is held back. On a
+    /// `Consumer` this is lazy head publication: `head` moves once per
+    /// fraction of the ring, or sooner when the producer may be stuck.
+    pub fn ring_fraction(len: usize, fraction: usize) -> Self {
+        let bytes = len.checked_div(fraction).map_or(0, |n| n.max(1));
+        return Self {
+            bytes,
+            items: usize::MAX,
+        };
+    }
+}
+
+/// Bytes committed through a handle but not yet published to the peer.
+#[derive(Default)]
+struct Deferred {
+    bytes: Cell<usize>,
+    items: Cell<usize>,
+}
+
+impl Deferred {
+    fn add(&self, count: usize) {
+        self.bytes.set(self.bytes.get() + count);
+        self.items.set(self.items.get() + 1);
+    }
+
+    fn due(&self, batch: Batch) -> bool {
+        return self.bytes.get() >= batch.bytes || self.items.get() >= batch.items;
+    }
+
+    fn take(&self) -> usize {
+        self.items.set(0);
+        return self.bytes.replace(0);
+    }
+}
+
+/// Writing end of a ring. Dropping it closes the producer side.
+pub struct Producer {
+    ring: RingBuf,
+    batch: Batch,
+    pending: Deferred,
+}
+
+/// Reading end of a ring. Dropping it closes the consumer side.
+pub struct Consumer {
+    ring: RingBuf,
+    batch: Batch,
+    pending: Deferred,
+}
+
+impl RingBuf {
+    /// Split the ring into its producer and consumer ends.
+    pub fn split(self) -> (Producer, Consumer) {
+        let consumer = Consumer {
+            ring: self.clone(),
+            batch: Batch::default(),
+            pending: Deferred::default(),
+        };
+        let producer = Producer {
+            ring: self,
+            batch: Batch::default(),
+            pending: Deferred::default(),
+        };
+        return (producer, consumer);
+    }
+
+    pub fn closed_flags(&self) -> u32 {
+        return self.state.closed.load(Ordering::Acquire);
+    }
+
+    pub(crate) fn close_side(&self, side: Side) {
+        let flag = match side {
+            Side::Producer => PRODUCER_CLOSED,
+            Side::Consumer => CONSUMER_CLOSED,
+        };
+        self.state.closed.fetch_or(flag, Ordering::SeqCst);
+        for side in [Side::Producer, Side::Consumer] {
+            if let Some(notifier) = self.notifier(side) {
+                notifier.notify();
+            }
+        }
+        let _guard = self.state.lock.lock().unwrap();
+        self.state.cond.notify_all();
+    }
+
+    // Commits through a handle are coalesced into one publication, which a
+    // stamped ring would read as a single chunk, and on an overwrite ring the
+    // producer may reuse memory the consumer was handed. The handles' zero-copy
+    // calls refuse both modes.
+    fn check_handle_mode(&self, op: &'static str) -> Result<()> {
+        self.check_mode(op, RingMode::Overwrite)?;
+        return self.check_mode(op, RingMode::Stamped);
+    }
+
+    /// Whether a write could place at least one byte: a stamped chunk needs
+    /// room for its header as well.
+    pub(crate) fn has_write_room(&self) -> bool {
+        let header = if self.mode == RingMode::Stamped {
+            STAMP
+        } else {
+            0
+        };
+        return self.available_space() > header;
+    }
+
+    fn waiting_flag(&self, side: Side) -> &AtomicBool {
+        return match side {
+            Side::Producer => &self.state.producer_waiting,
+            Side::Consumer => &self.state.consumer_waiting,
+        };
+    }
+
+    fn notifier(&self, side: Side) -> Option<&Arc<dyn Notify>> {
+        return match side {
+            Side::Producer => self.state.producer_notify.get(),
+            Side::Consumer => self.state.consumer_notify.get(),
+        };
+    }
+
+    /// Wake `side` if it is blocked in `wait_until` or armed in an event loop.
+    /// Call after publishing, with the trigger `produce`/`consume` returned.
+    pub(crate) fn wake(&self, side: Side, trigger: bool) {
+        fence(Ordering::SeqCst);
+        let waiting = self.waiting_flag(side).load(Ordering::Relaxed);
+        if let Some(notifier) = self.notifier(side)
+            && (trigger || waiting)
+        {
+            notifier.notify();
+        }
+        if waiting {
+            let _guard = self.state.lock.lock().unwrap();
+            self.state.cond.notify_all();
+        }
+    }
+
+    /// Announce that `side` is about to sleep in its event loop. Returns true if
+    /// it should not, because `ready` already holds or a side is closed.
+    pub(crate) fn arm(&self, side: Side, ready: impl Fn(&RingBuf) -> bool) -> bool {
+        self.waiting_flag(side).store(true, Ordering::Relaxed);
+        self.set_parked(side, true);
+        fence(Ordering::SeqCst);
+        return ready(self) || self.closed_flags() != 0;
+    }
+
+    pub(crate) fn disarm(&self, side: Side) {
+        self.waiting_flag(side).store(false, Ordering::Relaxed);
+        self.set_parked(side, false);
+    }
+
+    /// Block `side` until `ready` holds or any side is closed.
+    pub(crate) fn wait_until<F: Fn(&RingBuf) -> bool>(&self, side: Side, ready: F) {
+        let waiting = self.waiting_flag(side);
+
+        let mut guard = self.state.lock.lock().unwrap();
+        waiting.store(true, Ordering::Relaxed);
+        self.set_parked(side, true);
+        fence(Ordering::SeqCst);
+        while !ready(self) && self.closed_flags() == 0 {
+            guard = self.state.cond.wait(guard).unwrap();
+        }
+        waiting.store(false, Ordering::Relaxed);
+        self.set_parked(side, false);
+    }
+}
+
+impl Producer {
+    pub fn ring(&self) -> &RingBuf {
+        return &self.ring;
+    }
+
+    /// Publishes anything still deferred, then closes.
+    pub fn close(&self) {
+        self.flush();
+        self.ring.close_side(Side::Producer);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        return self.ring.closed_flags() != 0;
+    }
+
+    pub fn set_batch(&mut self, batch: Batch) {
+        self.batch = batch;
+    }
+
+    /// Have the consumer signal `notifier` when it frees space in a full ring,
+    /// or while this side is armed. Can be set once.
+    pub fn set_notifier(&self, notifier: Arc<dyn Notify>) -> bool {
+        return self.ring.state.producer_notify.set(notifier).is_ok();
+    }
+
+    /// Call before sleeping in an event loop on the notifier. Returns true if
+    /// there is space already (or the ring is closed) and sleeping would miss it.
+    pub fn arm(&self) -> bool {
+        return self.ring.arm(Side::Producer, RingBuf::has_write_room);
+    }
+
+    /// Call after waking up from the event loop.
+    pub fn disarm(&self) {
+        self.ring.disarm(Side::Producer);
+    }
+
+    /// Free region past the bytes committed so far, published or not.
+    /// Refused on stamped and overwrite rings.
+    pub fn get_space_buf(&self) -> Result<(u64, usize)> {
+        self.ring.check_handle_mode("Producer::get_space_buf")?;
+        let ring = &self.ring;
+        let head = ring.head.load(Ordering::Acquire);
+        let tail = ring
+            .tail
+            .load(Ordering::Relaxed)
+            .wrapping_add(self.pending.bytes.get() as u32);
+
+        let available = tail.wrapping_sub(head) as usize;
+        if available == ring.len() {
+            return Ok((0, 0));
+        }
+        let write_pos = (tail & ring.ring_mask) as usize;
+        let write_size = (ring.len() - available).min(ring.to_end(write_pos));
+        return Ok((ring.buf_ptr() as u64 + write_pos as u64, write_size));
+    }
+
+    /// Record `count` bytes written through `get_space_buf` as one item. They are
+    /// published once the batch fills up, or right away if the consumer has
+    /// caught up with everything published so far and may be waiting.
+    /// Returns whether this commit published.
+    pub fn commit(&self, count: usize) -> Result<bool> {
+        self.ring.check_handle_mode("Producer::commit")?;
+        self.pending.add(count);
+        let starved =
+            self.ring.head.load(Ordering::Relaxed) == self.ring.tail.load(Ordering::Relaxed);
+        if self.pending.due(self.batch)
+            || starved
+            || self.ring.state.consumer_waiting.load(Ordering::Relaxed)
+        {
+            self.flush();
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    /// Publish everything committed so far. Returns the ring's empty trigger.
+    pub fn flush(&self) -> bool {
+        let count = self.pending.take();
+        if count == 0 {
+            return false;
+        }
+        let trigger = self.ring.produce(count);
+        self.ring.wake(Side::Consumer, trigger);
+        return trigger;
+    }
+
+    /// Publish `count` bytes written through `get_space_buf`/`prepare_space_iovs`,
+    /// together with anything deferred.
+    pub fn produce(&self, count: usize) -> Result<bool> {
+        self.ring.check_handle_mode("Producer::produce")?;
+        self.pending.add(count);
+        return Ok(self.flush());
+    }
+
+    //return (ring was empty, how much written); never blocks
+    pub fn try_write(&mut self, buf: &[u8]) -> Result<(bool, usize)> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        self.flush();
+        let res = self.ring.write(buf)?;
+        if res.1 > 0 {
+            self.ring.wake(Side::Consumer, res.0);
+        }
+        return Ok(res);
+    }
+
+    /// Block until some space is free or either side is closed.
+    pub fn wait_space(&self) {
+        self.ring
+            .wait_until(Side::Producer, RingBuf::has_write_room);
+    }
+
+    /// Write at least one byte of `buf`, waiting for space if the ring is full.
+    /// Fails with `Error::Closed` once either side has been closed.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        loop {
+            let (_, n) = self.try_write(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.wait_space();
+        }
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Consumer {
+    pub fn ring(&self) -> &RingBuf {
+        return &self.ring;
+    }
+
+    /// Releases anything still deferred, then closes.
+    pub fn close(&self) {
+        self.flush();
+        self.ring.close_side(Side::Consumer);
+    }
+
+    /// True once the producer has closed and everything it wrote has been read.
+    pub fn is_eof(&self) -> bool {
+        return self.ring.closed_flags() & PRODUCER_CLOSED != 0
+            && self.ring.available_data_size() == self.pending.bytes.get();
+    }
+
+    pub fn set_batch(&mut self, batch: Batch) {
+        self.batch = batch;
+    }
+
+    /// Have the producer signal `notifier` when it publishes into an empty
+    /// ring, or while this side is armed. Can be set once.
+    pub fn set_notifier(&self, notifier: Arc<dyn Notify>) -> bool {
+        return self.ring.state.consumer_notify.set(notifier).is_ok();
+    }
+
+    /// Call before sleeping in an event loop on the notifier. Returns true if
+    /// data is already there (or the ring is closed) and sleeping would miss it.
+    pub fn arm(&self) -> bool {
+        let pending = self.pending.bytes.get();
+        return self
+            .ring
+            .arm(Side::Consumer, |ring| ring.available_data_size() > pending);
+    }
+
+    /// Call after waking up from the event loop.
+    pub fn disarm(&self) {
+        self.ring.disarm(Side::Consumer);
+    }
+
+    /// Data past the bytes committed so far, released or not.
+    /// Refused on stamped and overwrite rings.
+    pub fn get_data_buf(&self) -> Result<(u64, usize)> {
+        self.ring.check_handle_mode("Consumer::get_data_buf")?;
+        let ring = &self.ring;
+        let head = ring
+            .head
+            .load(Ordering::Relaxed)
+            .wrapping_add(self.pending.bytes.get() as u32);
+        let tail = ring.tail.load(Ordering::Acquire);
+
+        let available = tail.wrapping_sub(head) as usize;
+        if available == 0 {
+            return Ok((0, 0));
+        }
+        let read_pos = (head & ring.ring_mask) as usize;
+        let read_size = available.min(ring.to_end(read_pos));
+        return Ok((ring.buf_ptr() as u64 + read_pos as u64, read_size));
+    }
+
+    /// Copy data past the bytes committed so far into `buf`, following the wrap,
+    /// without committing it. Returns how many bytes were copied.
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        return self.ring.peek_at(self.pending.bytes.get(), buf);
+    }
+
+    /// Record `count` bytes read through `get_data_buf` as one item. The space
+    /// is released once the batch fills up, or right away if the ring looked
+    /// full to the producer and it may be waiting.
+    /// Returns whether this commit released.
+    pub fn commit(&self, count: usize) -> Result<bool> {
+        self.ring.check_handle_mode("Consumer::commit")?;
+        self.pending.add(count);
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let starved =
+            self.ring.tail.load(Ordering::Relaxed).wrapping_sub(head) as usize == self.ring.len();
+        if self.pending.due(self.batch)
+            || starved
+            || self.ring.state.producer_waiting.load(Ordering::Relaxed)
+        {
+            self.flush();
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    /// Release everything committed so far. Returns the ring's full trigger.
+    pub fn flush(&self) -> bool {
+        let count = self.pending.take();
+        if count == 0 {
+            return false;
+        }
+        let trigger = self.ring.consume(count);
+        self.ring.wake(Side::Producer, trigger);
+        return trigger;
+    }
+
+    /// Release `count` bytes read through `get_data_buf`/`prepare_data_iovs`,
+    /// together with anything deferred.
+    pub fn consume(&self, count: usize) -> Result<bool> {
+        self.ring.check_handle_mode("Consumer::consume")?;
+        self.pending.add(count);
+        return Ok(self.flush());
+    }
+
+    //return (ring was full, how much read); never blocks
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<(bool, usize)> {
+        if self.ring.closed_flags() & CONSUMER_CLOSED != 0 {
+            return Err(Error::Closed);
+        }
+        self.flush();
+        let res = self.ring.read(buf)?;
+        if res.1 > 0 {
+            self.ring.wake(Side::Producer, res.0);
+        }
+        return Ok(res);
+    }
+
+    /// Block until data is available or either side is closed.
+    pub fn wait_data(&self) {
+        self.ring
+            .wait_until(Side::Consumer, |ring| ring.available_data_size() > 0);
+    }
+
+    /// Read at least one byte into `buf`, waiting for data if the ring is empty.
+    /// Returns 0 only at end of stream: the producer closed and the ring is drained.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            // check the flag before the data, so bytes written before close are not missed
+            let producer_closed = self.ring.closed_flags() & PRODUCER_CLOSED != 0;
+            let (_, n) = self.try_read(buf)?;
+            if n > 0 || producer_closed || buf.is_empty() {
+                return Ok(n);
+            }
+            self.wait_data();
+        }
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn read_drains_then_reports_eof() {
+        let (mut producer, consumer) = RingBuf::new(2).split();
+        let writer = thread::spawn(move || {
+            let data: Vec<u8> = (0..100).collect();
+            let mut off = 0;
+            while off < data.len() {
+                off += producer.write(&data[off..]).unwrap();
+            }
+            // dropping the producer closes it
+        });
+
+        let mut out = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let n = consumer.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        writer.join().unwrap();
+        assert_eq!(out, (0..100).collect::<Vec<u8>>());
+        assert!(consumer.is_eof());
+    }
+
+    #[test]
+    fn consumer_close_wakes_blocked_writer() {
+        let (mut producer, consumer) = RingBuf::new(2).split();
+        let writer = thread::spawn(move || {
+            loop {
+                if let Err(err) = producer.write(&[1u8; 5]) {
+                    return err;
+                }
+            }
+        });
+
+        while consumer.ring().available_space() > 0 {
+            thread::yield_now();
+        }
+        drop(consumer);
+        assert!(matches!(writer.join().unwrap(), Error::Closed));
+    }
+
+    #[test]
+    fn commits_defer_until_batch_or_starved_peer() {
+        let (mut producer, mut consumer) = RingBuf::new(2).split();
+        producer.set_batch(Batch { bytes: 8, items: 4 });
+        consumer.set_batch(Batch { bytes: 8, items: 4 });
+        let ring = producer.ring().clone();
+
+        // the consumer has seen everything, so the first commit goes out at once
+        assert!(producer.commit(3).unwrap());
+        assert!(!producer.commit(3).unwrap());
+        assert!(!producer.commit(2).unwrap());
+        assert_eq!(ring.available_data_size(), 3);
+        assert_eq!(producer.get_space_buf().unwrap().1, 8);
+        assert!(producer.commit(3).unwrap());
+        assert_eq!(ring.available_data_size(), 11);
+        assert!(!producer.commit(5).unwrap());
+        assert!(!producer.flush());
+        assert_eq!(ring.available_data_size(), 16);
+
+        // a full ring means the producer may be blocked: release at once
+        assert!(consumer.commit(2).unwrap());
+        assert!(!consumer.commit(2).unwrap());
+        assert_eq!(consumer.get_data_buf().unwrap().1, 12);
+        assert_eq!(ring.available_space(), 2);
+        assert!(!consumer.flush());
+        assert_eq!(ring.available_space(), 4);
+    }
+
+    #[test]
+    fn ring_fraction_batch_publishes_head_lazily() {
+        // 64 byte ring releasing every quarter
+        let (mut producer, mut consumer) = RingBuf::new(8).split();
+        consumer.set_batch(Batch::ring_fraction(64, 4));
+        let ring = producer.ring().clone();
+        producer.write(&[7u8; 40]).unwrap();
+
+        for i in 0..15 {
+            assert_eq!(consumer.get_data_buf().unwrap().1, 40 - i);
+            consumer.commit(1).unwrap();
+        }
+        // the producer still sees 15 bytes it cannot reuse yet
+        assert_eq!(ring.stats().head, 0);
+        assert!(!consumer.is_eof());
+        consumer.commit(1).unwrap();
+        assert_eq!(ring.stats().head, 16);
+
+        // a ring that looked full releases straight away
+        producer.write(&[8u8; 40]).unwrap();
+        assert_eq!(ring.available_space(), 0);
+        consumer.commit(1).unwrap();
+        assert_eq!(ring.stats().head, 17);
+        // a zero fraction publishes on every commit
+        assert_eq!(Batch::ring_fraction(64, 0).bytes, 0);
+    }
+
+    #[test]
+    fn batched_handles_stream_in_order() {
+        let (mut producer, mut consumer) = RingBuf::new(8).split();
+        producer.set_batch(Batch {
+            bytes: 24,
+            items: usize::MAX,
+        });
+        consumer.set_batch(Batch {
+            bytes: 32,
+            items: usize::MAX,
+        });
+        let total = 4000;
+
+        let writer = thread::spawn(move || {
+            let mut produced = 0;
+            while produced < total {
+                let (addr, len) = producer.get_space_buf().unwrap();
+                if len == 0 {
+                    producer.flush();
+                    thread::yield_now();
+                    continue;
+                }
+                let byte = (produced % 251) as u8;
+                unsafe { *(addr as *mut u8) = byte };
+                producer.commit(1).unwrap();
+                produced += 1;
+            }
+            // dropping the producer flushes and closes
+        });
+
+        let mut consumed = 0;
+        while consumed < total {
+            let (addr, len) = consumer.get_data_buf().unwrap();
+            if len == 0 {
+                consumer.flush();
+                thread::yield_now();
+                continue;
+            }
+            assert_eq!(unsafe { *(addr as *const u8) }, (consumed % 251) as u8);
+            consumer.commit(1).unwrap();
+            consumed += 1;
+        }
+        writer.join().unwrap();
+        consumer.flush();
+        assert!(consumer.is_eof());
+    }
+
+    #[test]
+    fn stamped_ring_handles_copy_but_refuse_zero_copy() {
+        let (mut producer, consumer) = RingBuf::with_mode(8, RingMode::Stamped).split();
+        let unsupported = |res: Result<bool>| {
+            return matches!(res, Err(Error::Unsupported { .. }));
+        };
+        assert!(producer.get_space_buf().is_err());
+        assert!(unsupported(producer.commit(4)));
+        assert!(unsupported(producer.produce(4)));
+        assert!(consumer.get_data_buf().is_err());
+        assert!(unsupported(consumer.commit(4)));
+        assert!(unsupported(consumer.consume(4)));
+
+        // a 48 byte chunk leaves 8 bytes, too few for another header
+        assert_eq!(producer.write(&[1u8; 48]).unwrap(), 48);
+        assert_eq!(producer.ring().available_space(), 8);
+        assert!(!producer.arm());
+        producer.disarm();
+
+        let mut out = [0u8; 64];
+        assert_eq!(consumer.read(&mut out).unwrap(), 48);
+        assert!(out[..48].iter().all(|&b| b == 1));
+        assert!(producer.arm());
+        producer.disarm();
+    }
+
+    #[test]
+    fn producer_close_wakes_blocked_reader() {
+        let (producer, consumer) = RingBuf::new(2).split();
+        let reader = thread::spawn(move || consumer.read(&mut [0u8; 4]).unwrap());
+        while !producer.ring().diag.consumer_parked.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        producer.close();
+        assert_eq!(reader.join().unwrap(), 0);
+    }
+}
@@ -0,0 +1,422 @@
+//! Litmus tests of data-then-tail publication, run on real threads.
+//!
+//! A `Litmus` is a few threads of loads and stores over `u32` locations, each
+//! on its own cache line. Accesses with an `Ordering` are atomic; `Plain` ones
+//! are volatile reads and writes of the same memory, standing in for the
+//! payload copies `RingBuf` does through raw pointers. The runner executes a
+//! test many times and tallies the values its loads returned, so what the
+//! hardware actually shows can be set against the verdicts of `memory_model`.
+//!
+//! `cargo run --release -- litmus [iterations]` runs the suite.
+#![allow(dead_code, clippy::needless_return)]
+use crate::memory_model::{self, Expect};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+
+pub type Loc = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Plain,
+    Atomic(Ordering),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Store(Loc, u32, Access),
+    /// The value read goes to the next register of the outcome.
+    Load(Loc, Access),
+}
+
+/// Builder for one thread's ops.
+#[derive(Debug, Clone, Default)]
+pub struct Thread {
+    ops: Vec<Op>,
+}
+
+impl Thread {
+    pub fn store(mut self, loc: Loc, val: u32, ord: Ordering) -> Self {
+        self.ops.push(Op::Store(loc, val, Access::Atomic(ord)));
+        return self;
+    }
+
+    pub fn load(mut self, loc: Loc, ord: Ordering) -> Self {
+        self.ops.push(Op::Load(loc, Access::Atomic(ord)));
+        return self;
+    }
+
+    pub fn plain_store(mut self, loc: Loc, val: u32) -> Self {
+        self.ops.push(Op::Store(loc, val, Access::Plain));
+        return self;
+    }
+
+    pub fn plain_load(mut self, loc: Loc) -> Self {
+        self.ops.push(Op::Load(loc, Access::Plain));
+        return self;
+    }
+
+    fn loads(&self) -> usize {
+        return self
+            .ops
+            .iter()
+            .filter(|op| matches!(op, Op::Load(..)))
+            .count();
+    }
+}
+
+/// An outcome is every load's value, thread by thread in program order.
+pub type Outcome = Vec<u32>;
+
+type Predicate = Box<dyn Fn(&[u32]) -> bool + Sync>;
+
+pub struct Litmus {
+    pub name: &'static str,
+    threads: Vec<Thread>,
+    /// Outcomes that count as a violation.
+    forbidden: Predicate,
+}
+
+impl Litmus {
+    pub fn new(name: &'static str) -> Self {
+        return Self {
+            name,
+            threads: Vec::new(),
+            forbidden: Box::new(|_| false),
+        };
+    }
+
+    pub fn thread(mut self, build: impl FnOnce(Thread) -> Thread) -> Self {
+        self.threads.push(build(Thread::default()));
+        return self;
+    }
+
+    pub fn forbid(mut self, pred: impl Fn(&[u32]) -> bool + Sync + 'static) -> Self {
+        self.forbidden = Box::new(pred);
+        return self;
+    }
+
+    fn locations(&self) -> usize {
+        return self
+            .threads
+            .iter()
+            .flat_map(|t| &t.ops)
+            .map(|op| match op {
+                Op::Store(loc, ..) | Op::Load(loc, _) => loc + 1,
+            })
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Run the test `iterations` times, resetting every location to 0 between runs.
+    pub fn run(&self, iterations: usize) -> Tally {
+        let memory: Vec<Slot> = (0..self.locations()).map(|_| Slot::default()).collect();
+        let barrier = SpinBarrier::new(self.threads.len());
+
+        let regs: Vec<Vec<u32>> = thread::scope(|s| {
+            let workers: Vec<_> = self
+                .threads
+                .iter()
+                .enumerate()
+                .map(|(tid, t)| {
+                    let (memory, barrier) = (&memory, &barrier);
+                    s.spawn(move || {
+                        let mut regs = Vec::with_capacity(iterations * t.loads());
+                        for _ in 0..iterations {
+                            barrier.wait();
+                            for op in &t.ops {
+                                match *op {
+                                    Op::Store(loc, val, access) => memory[loc].store(val, access),
+                                    Op::Load(loc, access) => regs.push(memory[loc].load(access)),
+                                }
+                            }
+                            barrier.wait();
+                            if tid == 0 {
+                                memory.iter().for_each(|m| m.store(0, Access::Plain));
+                            }
+                        }
+                        regs
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        let mut counts = BTreeMap::new();
+        for i in 0..iterations {
+            let mut outcome = Outcome::new();
+            for (t, regs) in self.threads.iter().zip(&regs) {
+                let n = t.loads();
+                outcome.extend_from_slice(&regs[i * n..(i + 1) * n]);
+            }
+            *counts.entry(outcome).or_insert(0u64) += 1;
+        }
+        let violations = counts
+            .iter()
+            .filter(|(o, _)| (self.forbidden)(o))
+            .map(|(_, n)| n)
+            .sum();
+        return Tally {
+            name: self.name,
+            iterations,
+            counts,
+            violations,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tally {
+    pub name: &'static str,
+    pub iterations: usize,
+    pub counts: BTreeMap<Outcome, u64>,
+    /// Runs whose outcome the test forbids.
+    pub violations: u64,
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} runs, {} violations",
+            self.name, self.iterations, self.violations
+        )?;
+        for (outcome, n) in &self.counts {
+            writeln!(f, "  {:?}: {}", outcome, n)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+#[repr(align(64))]
+struct Slot(AtomicU32);
+
+impl Slot {
+    fn store(&self, val: u32, access: Access) {
+        match access {
+            Access::Atomic(ord) => self.0.store(val, ord),
+            Access::Plain => unsafe { self.0.as_ptr().write_volatile(val) },
+        }
+    }
+
+    fn load(&self, access: Access) -> u32 {
+        match access {
+            Access::Atomic(ord) => self.0.load(ord),
+            Access::Plain => unsafe { self.0.as_ptr().read_volatile() },
+        }
+    }
+}
+
+/// Generation-counting barrier; spinning keeps the threads released close together.
+struct SpinBarrier {
+    count: AtomicUsize,
+    generation: AtomicUsize,
+    threads: usize,
+}
+
+impl SpinBarrier {
+    fn new(threads: usize) -> Self {
+        return Self {
+            count: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            threads,
+        };
+    }
+
+    fn wait(&self) {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.count.fetch_add(1, Ordering::AcqRel) + 1 == self.threads {
+            self.count.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            return;
+        }
+        let mut spins = 0;
+        while self.generation.load(Ordering::Acquire) == generation {
+            // give way if the other threads share our core
+            spins += 1;
+            if spins < 1000 {
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// The programs of `ring_buffer.rkt` and the publication pattern of `RingBuf`.
+pub mod programs {
+    use super::*;
+    use crate::memory_model::programs::{DATA0, DATA1, EXPECT, HEAD, TAIL};
+    use Ordering::*;
+
+    const DATA0_L: Loc = DATA0 as Loc;
+    const DATA1_L: Loc = DATA1 as Loc;
+    const TAIL_L: Loc = TAIL as Loc;
+    const HEAD_L: Loc = HEAD as Loc;
+
+    /// Tail seen advanced but the paired slot holds something else.
+    fn stale(expect: &[Expect], outcome: &[u32]) -> bool {
+        return expect
+            .iter()
+            .any(|x| outcome[x.tail_read] >= x.tail && outcome[x.data_read] != x.data);
+    }
+
+    /// Same shape as the ring_buffer.rkt traces; data accesses are atomic with
+    /// the orders given there.
+    fn ring(
+        name: &'static str,
+        producer: [(Loc, u32, Ordering); 4],
+        tail_read: Ordering,
+        data_read: Ordering,
+        head: Ordering,
+    ) -> Litmus {
+        return Litmus::new(name)
+            .thread(|t| {
+                producer
+                    .iter()
+                    .fold(t, |t, &(loc, val, ord)| t.store(loc, val, ord))
+            })
+            .thread(|t| {
+                t.load(TAIL_L, tail_read)
+                    .load(DATA0_L, data_read)
+                    .store(HEAD_L, 1, head)
+                    .load(TAIL_L, tail_read)
+                    .load(DATA1_L, data_read)
+                    .store(HEAD_L, 0, head)
+            })
+            .forbid(|o| stale(&EXPECT, o));
+    }
+
+    pub fn p1() -> Litmus {
+        let p = [
+            (DATA0_L, 1, SeqCst),
+            (TAIL_L, 1, SeqCst),
+            (DATA1_L, 2, SeqCst),
+            (TAIL_L, 2, SeqCst),
+        ];
+        return ring("P1 (all SC)", p, SeqCst, SeqCst, SeqCst);
+    }
+
+    pub fn p2() -> Litmus {
+        let p = [
+            (TAIL_L, 1, Relaxed),
+            (DATA0_L, 1, Relaxed),
+            (TAIL_L, 2, Relaxed),
+            (DATA1_L, 2, Relaxed),
+        ];
+        return ring("P2 (relaxed, tail first)", p, Relaxed, Relaxed, Relaxed);
+    }
+
+    pub fn p3() -> Litmus {
+        let p = [
+            (DATA0_L, 1, Release),
+            (TAIL_L, 1, Release),
+            (DATA1_L, 2, Release),
+            (TAIL_L, 2, Release),
+        ];
+        return ring("P3 (all release/acquire)", p, Acquire, Acquire, Relaxed);
+    }
+
+    pub fn p4() -> Litmus {
+        let p = [
+            (DATA0_L, 1, Relaxed),
+            (TAIL_L, 1, Release),
+            (DATA1_L, 2, Relaxed),
+            (TAIL_L, 2, Release),
+        ];
+        return ring("P4 (release tail)", p, Acquire, Relaxed, Relaxed);
+    }
+
+    pub fn p5() -> Litmus {
+        let p = [
+            (DATA0_L, 1, Relaxed),
+            (TAIL_L, 1, Relaxed),
+            (DATA1_L, 2, Relaxed),
+            (TAIL_L, 2, Release),
+        ];
+        return ring("P5 (first tail relaxed)", p, Acquire, Relaxed, Relaxed);
+    }
+
+    /// `RingBuf`'s zero-copy path: payload copied in plainly, `produce` stores the
+    /// tail with Release, `get_data_buf` loads it with SeqCst before copying out.
+    pub fn ring_buf() -> Litmus {
+        return Litmus::new("RingBuf produce/get_data_buf")
+            .thread(|t| {
+                t.plain_store(DATA0_L, 1)
+                    .store(TAIL_L, 1, Release)
+                    .plain_store(DATA1_L, 2)
+                    .store(TAIL_L, 2, Release)
+            })
+            .thread(|t| {
+                t.load(TAIL_L, SeqCst)
+                    .plain_load(DATA0_L)
+                    .store(HEAD_L, 1, Release)
+                    .load(TAIL_L, SeqCst)
+                    .plain_load(DATA1_L)
+                    .store(HEAD_L, 0, Release)
+            })
+            .forbid(|o| stale(&EXPECT, o));
+    }
+
+    /// Each litmus test with the `memory_model` program it mirrors.
+    pub fn suite() -> Vec<(Litmus, Option<memory_model::Program>)> {
+        use crate::memory_model::programs as model;
+        return vec![
+            (p1(), Some(model::p1())),
+            (p2(), Some(model::p2())),
+            (p3(), Some(model::p3())),
+            (p4(), Some(model::p4())),
+            (p5(), Some(model::p5())),
+            (ring_buf(), None),
+        ];
+    }
+}
+
+/// Run the suite and print each tally next to the model's verdict.
+pub fn run_suite(iterations: usize) {
+    for (test, program) in programs::suite() {
+        let tally = test.run(iterations);
+        let verdict = match program {
+            Some(p) => match memory_model::check(&p, &memory_model::programs::EXPECT) {
+                Some(_) => "model: violation reachable (SAT)",
+                None => "model: no violation (UNSAT)",
+            },
+            None => "model: not modelled",
+        };
+        println!("{}", verdict);
+        print!("{}", tally);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::programs::*;
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "plain accesses race by design")]
+    fn correct_publication_is_never_stale() {
+        for test in [p1(), p3(), p4(), ring_buf()] {
+            let tally = test.run(5_000);
+            assert_eq!(tally.counts.values().sum::<u64>(), 5_000);
+            assert_eq!(tally.violations, 0, "{}", tally);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "plain accesses race by design")]
+    fn single_thread_outcome_is_deterministic() {
+        let test = Litmus::new("single thread")
+            .thread(|t| {
+                t.store(0, 1, Ordering::Relaxed)
+                    .load(0, Ordering::Relaxed)
+                    .plain_load(1)
+            })
+            .forbid(|o| o != [1, 0]);
+        let tally = test.run(1000);
+        assert_eq!(tally.counts.len(), 1);
+        assert_eq!(tally.violations, 0);
+    }
+}
@@ -1,10 +1,37 @@
+mod builder;
+mod error;
+mod flow;
+mod fragment;
+mod handle;
+#[cfg(feature = "integrity")]
+mod integrity;
+mod litmus;
+mod memory_model;
+mod notify;
+#[cfg(loom)]
+mod order_analysis;
 mod ring_buffer;
 mod ring_buffer_seq;
-use ring_buffer::RingBuf;
+mod ring_set;
+mod slot;
+#[cfg(feature = "stats")]
+mod stats;
+mod storage;
+#[cfg(test)]
+mod tests;
+mod transfer;
+#[cfg(feature = "typed")]
+mod typed;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod watchdog;
+use handle::Batch;
+use ring_buffer::{RingBuf, RingMode, RingStats, Side};
 use ring_buffer_seq::RingBufSeq;
 use std::mem::size_of;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use watchdog::Watchdog;
 
 trait RingBufferApi: Send + Sync + Clone + 'static {
     fn new(count: usize) -> Self;
@@ -14,6 +41,14 @@ trait RingBufferApi: Send + Sync + Clone + 'static {
     fn consume(&self, count: usize) -> bool;
     fn write(&mut self, buf: &[u8]) -> Result<(bool, usize), ring_buffer::Error>;
     fn read(&self, buf: &mut [u8]) -> Result<(bool, usize), ring_buffer::Error>;
+    fn set_parked(&self, _side: Side, _parked: bool) {}
+    fn watchdog(
+        &self,
+        _timeout: Duration,
+        _on_stall: Box<dyn FnMut(&RingStats) + Send>,
+    ) -> Option<Watchdog> {
+        None
+    }
 }
 
 impl RingBufferApi for RingBuf {
@@ -38,6 +73,16 @@ impl RingBufferApi for RingBuf {
     fn read(&self, buf: &mut [u8]) -> Result<(bool, usize), ring_buffer::Error> {
         self.read(buf)
     }
+    fn set_parked(&self, side: Side, parked: bool) {
+        self.set_parked(side, parked)
+    }
+    fn watchdog(
+        &self,
+        timeout: Duration,
+        on_stall: Box<dyn FnMut(&RingStats) + Send>,
+    ) -> Option<Watchdog> {
+        Some(RingBuf::watchdog(self, timeout, on_stall))
+    }
 }
 
 impl RingBufferApi for RingBufSeq {
@@ -58,11 +103,9 @@ impl RingBufferApi for RingBufSeq {
     }
     fn write(&mut self, buf: &[u8]) -> Result<(bool, usize), ring_buffer::Error> {
         self.write(buf)
-            .map_err(|_| ring_buffer::Error::SysError(ring_buffer::SysErr::EINVAL))
     }
     fn read(&self, buf: &mut [u8]) -> Result<(bool, usize), ring_buffer::Error> {
         self.read(buf)
-            .map_err(|_| ring_buffer::Error::SysError(ring_buffer::SysErr::EINVAL))
     }
 }
 
@@ -106,7 +149,10 @@ fn run_benchmark_raw<T: RingBufferApi>(name: &str, batch_size: usize) {
                                 producer_ring.produce(batch_size * size_of::<u64>());
                             }
                         }
-                        producer_ring.produce(to_write_items % batch_size * size_of::<u64>());
+                        let rest = to_write_items % batch_size;
+                        if rest > 0 {
+                            producer_ring.produce(rest * size_of::<u64>());
+                        }
                     }
                     produced += to_write_items * 8;
                 } else {
@@ -146,7 +192,10 @@ fn run_benchmark_raw<T: RingBufferApi>(name: &str, batch_size: usize) {
                                 consumer_ring.consume(batch_size * size_of::<u64>());
                             }
                         }
-                        consumer_ring.consume(len_items % batch_size * size_of::<u64>());
+                        let rest = len_items % batch_size;
+                        if rest > 0 {
+                            consumer_ring.consume(rest * size_of::<u64>());
+                        }
                     }
                     consumed += len_items * 8;
                 } else {
@@ -169,6 +218,143 @@ fn run_benchmark_raw<T: RingBufferApi>(name: &str, batch_size: usize) {
     println!("--------------------------------------------------");
 }
 
+/// Same transfer as `run_benchmark_raw` with batch size 1, but through the
+/// handles: every item is committed and the handles decide when to publish.
+fn run_benchmark_batched(name: &str, producer_batch: Batch, consumer_batch: Batch) {
+    let count = 131072; // 1MB buffer
+    let (mut producer, mut consumer) = RingBuf::new(count).split();
+    producer.set_batch(producer_batch);
+    consumer.set_batch(consumer_batch);
+    let total_items = 1024 * 1024 * 128 / 8;
+
+    println!(
+        "Starting batched benchmark (commit per item) for {}: {:?} / {:?}",
+        name, producer_batch, consumer_batch
+    );
+    let start = Instant::now();
+
+    let producer_affinity = core_affinity::get_core_ids().unwrap()[12];
+    let consumer_affinity = core_affinity::get_core_ids().unwrap()[14];
+
+    let producer = thread::spawn(move || {
+        core_affinity::set_for_current(producer_affinity);
+        let mut produced = 0;
+        while produced < total_items {
+            let (addr, len) = producer.get_space_buf().unwrap();
+            let items = std::cmp::min(len / 8, total_items - produced);
+            if items == 0 {
+                producer.flush();
+                thread::yield_now();
+                continue;
+            }
+            let ptr = addr as *mut u64;
+            for i in 0..items {
+                unsafe { *ptr.add(i) = (produced + i) as u64 };
+                producer.commit(size_of::<u64>()).unwrap();
+            }
+            produced += items;
+        }
+    });
+
+    let consumer = thread::spawn(move || {
+        core_affinity::set_for_current(consumer_affinity);
+        let mut consumed = 0;
+        while consumed < total_items {
+            let (addr, len) = consumer.get_data_buf().unwrap();
+            let items = len / 8;
+            if items == 0 {
+                consumer.flush();
+                thread::yield_now();
+                continue;
+            }
+            let ptr = addr as *const u64;
+            for i in 0..items {
+                let val = unsafe { *ptr.add(i) };
+                assert_eq!(
+                    val,
+                    (consumed + i) as u64,
+                    "mismatch at index {}",
+                    consumed + i
+                );
+                consumer.commit(size_of::<u64>()).unwrap();
+            }
+            consumed += items;
+        }
+    });
+
+    producer.join().unwrap();
+    consumer.join().unwrap();
+
+    let duration = start.elapsed();
+    let mb = (total_items * 8) as f64 / 1024.0 / 1024.0;
+    let seconds = duration.as_secs_f64();
+    println!("{}: Transferred {} MB in {:.4} seconds", name, mb, seconds);
+    println!("{}: Throughput: {:.2} MB/s", name, mb / seconds);
+    println!("--------------------------------------------------");
+}
+
+/// Same transfer as `run_benchmark_raw`, but the payload is not a counter: the
+/// chunk stamps are all that would catch a stale read.
+fn run_benchmark_stamped(name: &str, chunk: usize) {
+    let count = 131072; // 1MB buffer
+    let ring = RingBuf::with_mode(count, RingMode::Stamped);
+    let total_bytes = 1024 * 1024 * 128;
+
+    let producer_ring = ring.clone();
+    let consumer_ring = ring.clone();
+
+    println!(
+        "Starting stamped benchmark for {}: {} byte chunks",
+        name, chunk
+    );
+    let start = Instant::now();
+
+    let producer_affinity = core_affinity::get_core_ids().unwrap()[12];
+    let consumer_affinity = core_affinity::get_core_ids().unwrap()[14];
+
+    let producer = thread::spawn(move || {
+        core_affinity::set_for_current(producer_affinity);
+        let mut produced = 0;
+        while produced < total_bytes {
+            let (addr, len) = producer_ring.get_space_buf();
+            let n = std::cmp::min(std::cmp::min(len, chunk), total_bytes - produced);
+            if n == 0 {
+                thread::yield_now();
+                continue;
+            }
+            unsafe { std::ptr::write_bytes(addr as *mut u8, 0xa5, n) };
+            producer_ring.produce(n);
+            produced += n;
+        }
+    });
+
+    let consumer = thread::spawn(move || {
+        core_affinity::set_for_current(consumer_affinity);
+        let mut consumed = 0;
+        while consumed < total_bytes {
+            let (_, len) = consumer_ring
+                .get_stamped_buf()
+                .expect("stale chunk in a stamped ring");
+            if len == 0 {
+                thread::yield_now();
+                continue;
+            }
+            consumer_ring.consume(len);
+            consumed += len;
+        }
+    });
+
+    producer.join().unwrap();
+    consumer.join().unwrap();
+
+    let duration = start.elapsed();
+    let mb = total_bytes as f64 / 1024.0 / 1024.0;
+    let seconds = duration.as_secs_f64();
+    println!("{}: Transferred {} MB in {:.4} seconds", name, mb, seconds);
+    println!("{}: Throughput: {:.2} MB/s", name, mb / seconds);
+    println!("--------------------------------------------------");
+}
+
 fn run_benchmark_read_write<T: RingBufferApi>(name: &str) {
     let count = 131072; // 1MB buffer
     let ring = T::new(count);
@@ -244,12 +430,40 @@ fn run_benchmark_read_write<T: RingBufferApi>(name: &str) {
 }
 
 fn main() {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("litmus") {
+        let iterations = args
+            .next()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(1_000_000);
+        litmus::run_suite(iterations);
+        return;
+    }
+
     run_benchmark_raw::<RingBuf>("RingBuf (Optimized)", 1);
     run_benchmark_raw::<RingBufSeq>("RingBufSeq (Sequential)", 1);
 
     run_benchmark_raw::<RingBuf>("RingBuf (Optimized) (Large Batch Size)", usize::MAX);
     run_benchmark_raw::<RingBufSeq>("RingBufSeq (Sequential) (Large Batch Size)", usize::MAX);
 
+    run_benchmark_batched(
+        "RingBuf (Optimized) (Unbatched handles)",
+        Batch::default(),
+        Batch::default(),
+    );
+    let batch = Batch {
+        bytes: 4096,
+        items: usize::MAX,
+    };
+    run_benchmark_batched("RingBuf (Optimized) (Batched handles)", batch, batch);
+    run_benchmark_batched(
+        "RingBuf (Optimized) (Lazy consumer head)",
+        Batch::default(),
+        Batch::ring_fraction(131072 * 8, 8),
+    );
+
+    run_benchmark_stamped("RingBuf (Stamped)", 4096);
+
     run_benchmark_read_write::<RingBuf>("RingBuf (Optimized)");
     run_benchmark_read_write::<RingBufSeq>("RingBufSeq (Sequential)");
 
@@ -269,6 +483,7 @@ fn main() {
     println!("RingBuf passed deadlock test (Unexpected if it didn't hang).");
 }
 
+#[allow(clippy::collapsible_if)]
 fn run_deadlock_test<T: RingBufferApi>(name: &str) {
     let count = 131072; // 1MB buffer
     let ring = T::new(count);
@@ -291,6 +506,14 @@ fn run_deadlock_test<T: RingBufferApi>(name: &str) {
     let consumer_cv = Arc::new((Mutex::new(()), Condvar::new()));
     let barrier = Arc::new(Barrier::new(2));
 
+    let stall_name = name.to_string();
+    let _watchdog = ring.watchdog(
+        Duration::from_secs(5),
+        Box::new(move |stats| {
+            println!("{}: no progress for 5s: {:?}", stall_name, stats);
+        }),
+    );
+
     let p_cv = producer_cv.clone();
     let c_cv = consumer_cv.clone();
     let b_prod = barrier.clone();
@@ -304,7 +527,9 @@ fn run_deadlock_test<T: RingBufferApi>(name: &str) {
         while produced < total_bytes {
             let (addr, len) = producer_ring.get_space_buf();
             if len == 0 {
+                producer_ring.set_parked(Side::Producer, true);
                 guard = p_cv.1.wait(guard).unwrap();
+                producer_ring.set_parked(Side::Producer, false);
                 continue;
             }
 
@@ -360,7 +585,9 @@ fn run_deadlock_test<T: RingBufferApi>(name: &str) {
         while consumed < total_bytes {
             let (addr, len) = consumer_ring.get_data_buf();
             if len == 0 {
+                consumer_ring.set_parked(Side::Consumer, true);
                 guard = c_cv_c.1.wait(guard).unwrap();
+                consumer_ring.set_parked(Side::Consumer, false);
                 continue;
             }
 
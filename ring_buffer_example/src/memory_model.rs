@@ -0,0 +1,612 @@
+//! Axiomatic memory-model checker, a Rust port of `model.rkt` / `verify.rkt`.
+//!
+//! A `Program` is a list of events per thread. The checker enumerates every
+//! candidate execution (a reads-from choice for each read and a coherence order
+//! per address), keeps the consistent ones, and reports executions in which the
+//! consumer observes a tail advance but stale data, or misses an overwrite.
+//!
+//! Traces are tiny, so enumeration is exhaustive instead of going through a
+//! solver. One deliberate difference from `verify.rkt`: rather than its ad hoc
+//! tail/data pairing rule, release writes are ordered after everything before
+//! them in program order and acquire reads before everything after them. That
+//! gives the same verdicts on P1-P5 without the model knowing the ring layout.
+#![allow(dead_code, clippy::needless_return)]
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemOrder {
+    Sc,
+    Acq,
+    Rel,
+    Rlx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+    RdmaWrite,
+}
+
+/// Reads carry no value; it comes from the write they read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub id: i32,
+    pub thread: i32,
+    pub kind: Kind,
+    pub addr: u32,
+    pub val: u32,
+    pub order: MemOrder,
+}
+
+impl Event {
+    pub fn read(id: i32, thread: i32, addr: u32, order: MemOrder) -> Self {
+        return Self {
+            id,
+            thread,
+            kind: Kind::Read,
+            addr,
+            val: 0,
+            order,
+        };
+    }
+
+    pub fn write(id: i32, thread: i32, addr: u32, val: u32, order: MemOrder) -> Self {
+        return Self {
+            id,
+            thread,
+            kind: Kind::Write,
+            addr,
+            val,
+            order,
+        };
+    }
+
+    pub fn rdma_write(id: i32, thread: i32, addr: u32, val: u32, order: MemOrder) -> Self {
+        return Self {
+            id,
+            thread,
+            kind: Kind::RdmaWrite,
+            addr,
+            val,
+            order,
+        };
+    }
+
+    pub fn is_read(&self) -> bool {
+        return self.kind == Kind::Read;
+    }
+
+    pub fn is_write(&self) -> bool {
+        return self.kind != Kind::Read;
+    }
+
+    /// CPU-local access; RDMA writes are not ordered by plain program order.
+    pub fn is_local(&self) -> bool {
+        return self.kind != Kind::RdmaWrite;
+    }
+
+    fn is_release(&self) -> bool {
+        return self.is_write() && matches!(self.order, MemOrder::Rel | MemOrder::Sc);
+    }
+
+    fn is_acquire(&self) -> bool {
+        return self.is_read() && matches!(self.order, MemOrder::Acq | MemOrder::Sc);
+    }
+}
+
+/// Events of all threads. Every address starts with an SC write of 0 by
+/// thread -1, added by `Program::new`.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub events: Vec<Event>,
+}
+
+impl Program {
+    pub fn new(trace: Vec<Event>) -> Self {
+        let mut addrs: Vec<u32> = trace.iter().map(|e| e.addr).collect();
+        addrs.sort();
+        addrs.dedup();
+        let mut events: Vec<Event> = addrs
+            .iter()
+            .enumerate()
+            .map(|(i, &addr)| Event::write(-(i as i32) - 1, -1, addr, 0, MemOrder::Sc))
+            .collect();
+        events.extend(trace);
+        return Self { events };
+    }
+
+    fn is_init(&self, e: usize) -> bool {
+        return self.events[e].thread < 0;
+    }
+
+    /// Indices of the reads, in trace order.
+    pub fn reads(&self) -> Vec<usize> {
+        return (0..self.events.len())
+            .filter(|&e| self.events[e].is_read())
+            .collect();
+    }
+
+    fn writes_to(&self, addr: u32) -> Vec<usize> {
+        return (0..self.events.len())
+            .filter(|&e| self.events[e].is_write() && self.events[e].addr == addr)
+            .collect();
+    }
+
+    /// Program order: same thread, earlier id.
+    pub fn po(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (&self.events[a], &self.events[b]);
+        return a.thread >= 0 && a.thread == b.thread && a.id < b.id;
+    }
+
+    /// Preserved program order: local accesses stay in order, a release write
+    /// stays after everything before it, an acquire read before everything after
+    /// it, and SC events stay in order among themselves.
+    pub fn ppo(&self, a: usize, b: usize) -> bool {
+        if !self.po(a, b) {
+            return false;
+        }
+        let (ea, eb) = (&self.events[a], &self.events[b]);
+        return (ea.is_local() && eb.is_local())
+            || eb.is_release()
+            || ea.is_acquire()
+            || (ea.order == MemOrder::Sc && eb.order == MemOrder::Sc);
+    }
+
+    /// Every consistent execution of the program.
+    pub fn executions(&self) -> Vec<Execution<'_>> {
+        let n = self.events.len();
+        let reads = self.reads();
+        let mut addrs: Vec<u32> = self.events.iter().map(|e| e.addr).collect();
+        addrs.sort();
+        addrs.dedup();
+
+        // coherence: init write first, then any permutation of the others
+        let mut co_choices: Vec<Vec<Vec<usize>>> = Vec::new();
+        for &addr in &addrs {
+            let writes = self.writes_to(addr);
+            let (init, rest): (Vec<usize>, Vec<usize>) =
+                writes.iter().partition(|&&w| self.is_init(w));
+            co_choices.push(
+                permutations(&rest)
+                    .into_iter()
+                    .map(|p| init.iter().copied().chain(p).collect())
+                    .collect(),
+            );
+        }
+        let rf_choices: Vec<Vec<usize>> = reads
+            .iter()
+            .map(|&r| self.writes_to(self.events[r].addr))
+            .collect();
+
+        let mut out = Vec::new();
+        for co in product(&co_choices) {
+            let mut co_rank = vec![0; n];
+            for order in &co {
+                for (rank, &w) in order.iter().enumerate() {
+                    co_rank[w] = rank;
+                }
+            }
+            for sources in product(&rf_choices) {
+                let mut rf = vec![None; n];
+                for (&r, &w) in reads.iter().zip(&sources) {
+                    rf[r] = Some(w);
+                }
+                let exec = Execution {
+                    program: self,
+                    rf,
+                    co_rank: co_rank.clone(),
+                };
+                if exec.is_consistent() {
+                    out.push(exec);
+                }
+            }
+        }
+        return out;
+    }
+}
+
+/// One candidate execution: where each read reads from, and the position of
+/// each write in the coherence order of its address.
+#[derive(Clone)]
+pub struct Execution<'a> {
+    program: &'a Program,
+    rf: Vec<Option<usize>>,
+    co_rank: Vec<usize>,
+}
+
+impl Execution<'_> {
+    fn event(&self, e: usize) -> &Event {
+        return &self.program.events[e];
+    }
+
+    /// Value returned by read `r`.
+    pub fn value(&self, r: usize) -> u32 {
+        return self.event(self.rf[r].unwrap()).val;
+    }
+
+    pub fn rf(&self, w: usize, r: usize) -> bool {
+        return self.rf[r] == Some(w);
+    }
+
+    pub fn co(&self, a: usize, b: usize) -> bool {
+        let (ea, eb) = (self.event(a), self.event(b));
+        return ea.is_write()
+            && eb.is_write()
+            && ea.addr == eb.addr
+            && self.co_rank[a] < self.co_rank[b];
+    }
+
+    /// From-read: `r` reads a write that is coherence-before `w`.
+    pub fn fr(&self, r: usize, w: usize) -> bool {
+        return match self.rf[r] {
+            Some(src) => self.co(src, w),
+            None => false,
+        };
+    }
+
+    /// Release-acquire synchronization: `rf(w_rel, r_acq)` orders everything
+    /// before `w_rel` in its thread before everything after `r_acq` in its thread.
+    pub fn ra_hb(&self, a: usize, b: usize) -> bool {
+        let n = self.program.events.len();
+        return (0..n).any(|r| {
+            self.event(r).is_acquire()
+                && self.rf[r].is_some_and(|w| {
+                    self.event(w).is_release() && self.program.po(a, w) && self.program.po(r, b)
+                })
+        });
+    }
+
+    /// acyclic(ppo | rf | co | fr | ra_hb)
+    pub fn is_consistent(&self) -> bool {
+        let n = self.program.events.len();
+        let mut succ = vec![Vec::new(); n];
+        let mut indegree = vec![0; n];
+        for (a, succ) in succ.iter_mut().enumerate() {
+            for (b, indegree) in indegree.iter_mut().enumerate() {
+                if a != b
+                    && (self.program.ppo(a, b)
+                        || self.rf(a, b)
+                        || self.co(a, b)
+                        || self.fr(a, b)
+                        || self.ra_hb(a, b))
+                {
+                    succ.push(b);
+                    *indegree += 1;
+                }
+            }
+        }
+
+        let mut ready: Vec<usize> = (0..n).filter(|&e| indegree[e] == 0).collect();
+        let mut seen = 0;
+        while let Some(e) = ready.pop() {
+            seen += 1;
+            for &s in &succ[e] {
+                indegree[s] -= 1;
+                if indegree[s] == 0 {
+                    ready.push(s);
+                }
+            }
+        }
+        return seen == n;
+    }
+}
+
+impl fmt::Debug for Execution<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        for r in self.program.reads() {
+            let e = self.event(r);
+            let w = self.event(self.rf[r].unwrap());
+            list.entry(&format_args!(
+                "read {} @{} = {} (from {})",
+                e.id, e.addr, w.val, w.id
+            ));
+        }
+        return list.finish();
+    }
+}
+
+/// The consumer reads `tail_read` and then `data_read`; once it sees the tail
+/// at `tail`, the data must be `data`. Indices are positions among the reads.
+#[derive(Debug, Clone, Copy)]
+pub struct Expect {
+    pub tail_read: usize,
+    pub data_read: usize,
+    pub tail: u32,
+    pub data: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Tail advance observed but the slot still held something else.
+    StaleData { round: usize, seen: u32 },
+    /// A later round returned the earlier round's value.
+    MissedOverwrite { round: usize },
+}
+
+#[derive(Debug)]
+pub struct Counterexample<'a> {
+    pub execution: Execution<'a>,
+    pub violations: Vec<Violation>,
+}
+
+/// Look for a consistent execution in which the consumer sees every expected
+/// tail (the progress assumption of `verify.rkt`) but some round goes wrong.
+pub fn check<'a>(program: &'a Program, expect: &[Expect]) -> Option<Counterexample<'a>> {
+    return check_assuming(program, expect, &[]);
+}
+
+/// `check`, restricted further to executions where read `i` (a position among
+/// the reads) returns `v` for every `(i, v)` in `assume`.
+pub fn check_assuming<'a>(
+    program: &'a Program,
+    expect: &[Expect],
+    assume: &[(usize, u32)],
+) -> Option<Counterexample<'a>> {
+    let reads = program.reads();
+    for exec in program.executions() {
+        let value = |i: usize| exec.value(reads[i]);
+        if expect.iter().any(|x| value(x.tail_read) != x.tail)
+            || assume.iter().any(|&(i, v)| value(i) != v)
+        {
+            continue;
+        }
+
+        let mut violations = Vec::new();
+        for (round, x) in expect.iter().enumerate() {
+            let seen = value(x.data_read);
+            if seen != x.data {
+                violations.push(Violation::StaleData { round, seen });
+            }
+        }
+        for (round, pair) in expect.windows(2).enumerate() {
+            let (prev, next) = (pair[0], pair[1]);
+            if value(prev.data_read) == prev.data
+                && prev.data != next.data
+                && value(next.data_read) == prev.data
+            {
+                violations.push(Violation::MissedOverwrite { round: round + 1 });
+            }
+        }
+        if !violations.is_empty() {
+            return Some(Counterexample {
+                execution: exec,
+                violations,
+            });
+        }
+    }
+    return None;
+}
+
+fn permutations(items: &[usize]) -> Vec<Vec<usize>> {
+    if items.is_empty() {
+        return vec![Vec::new()];
+    }
+    let mut out = Vec::new();
+    for i in 0..items.len() {
+        let mut rest = items.to_vec();
+        let first = rest.remove(i);
+        for mut p in permutations(&rest) {
+            p.insert(0, first);
+            out.push(p);
+        }
+    }
+    return out;
+}
+
+/// Cartesian product: one element from each choice list.
+fn product<T: Clone>(choices: &[Vec<T>]) -> Vec<Vec<T>> {
+    let mut out = vec![Vec::new()];
+    for options in choices {
+        out = out
+            .into_iter()
+            .flat_map(|prefix| {
+                options.iter().map(move |o| {
+                    let mut v = prefix.clone();
+                    v.push(o.clone());
+                    v
+                })
+            })
+            .collect();
+    }
+    return out;
+}
+
+/// The demo programs from `ring_buffer.rkt`: a producer publishing two slots
+/// over RDMA, and a consumer reading tail then data twice.
+pub mod programs {
+    use super::MemOrder::{self, *};
+    use super::{Event, Expect, Program};
+
+    pub const DATA0: u32 = 0;
+    pub const DATA1: u32 = 1;
+    pub const TAIL: u32 = 2;
+    pub const HEAD: u32 = 3;
+
+    /// Reads are, in order: tail, data0, tail, data1.
+    pub const EXPECT: [Expect; 2] = [
+        Expect {
+            tail_read: 0,
+            data_read: 1,
+            tail: 1,
+            data: 1,
+        },
+        Expect {
+            tail_read: 2,
+            data_read: 3,
+            tail: 2,
+            data: 2,
+        },
+    ];
+
+    /// `data`/`tail` orders on the producer, `tail_read`/`data_read` on the consumer.
+    fn ring(
+        producer: [(u32, u32, MemOrder); 4],
+        tail_read: MemOrder,
+        data_read: MemOrder,
+        head: MemOrder,
+    ) -> Program {
+        let mut trace: Vec<Event> = producer
+            .iter()
+            .enumerate()
+            .map(|(i, &(addr, val, order))| Event::rdma_write(i as i32 + 1, 1, addr, val, order))
+            .collect();
+        trace.extend([
+            Event::read(5, 2, TAIL, tail_read),
+            Event::read(6, 2, DATA0, data_read),
+            Event::write(7, 2, HEAD, 1, head),
+            Event::read(8, 2, TAIL, tail_read),
+            Event::read(9, 2, DATA1, data_read),
+            Event::write(10, 2, HEAD, 0, head),
+        ]);
+        return Program::new(trace);
+    }
+
+    /// All SC.
+    pub fn p1() -> Program {
+        return ring(
+            [(DATA0, 1, Sc), (TAIL, 1, Sc), (DATA1, 2, Sc), (TAIL, 2, Sc)],
+            Sc,
+            Sc,
+            Sc,
+        );
+    }
+
+    /// All relaxed, and the tail is written before the data.
+    pub fn p2() -> Program {
+        return ring(
+            [
+                (TAIL, 1, Rlx),
+                (DATA0, 1, Rlx),
+                (TAIL, 2, Rlx),
+                (DATA1, 2, Rlx),
+            ],
+            Rlx,
+            Rlx,
+            Rlx,
+        );
+    }
+
+    /// Every write release, every read acquire.
+    pub fn p3() -> Program {
+        return ring(
+            [
+                (DATA0, 1, Rel),
+                (TAIL, 1, Rel),
+                (DATA1, 2, Rel),
+                (TAIL, 2, Rel),
+            ],
+            Acq,
+            Acq,
+            Rlx,
+        );
+    }
+
+    /// Data relaxed, tail release, tail reads acquire.
+    pub fn p4() -> Program {
+        return ring(
+            [
+                (DATA0, 1, Rlx),
+                (TAIL, 1, Rel),
+                (DATA1, 2, Rlx),
+                (TAIL, 2, Rel),
+            ],
+            Acq,
+            Rlx,
+            Rlx,
+        );
+    }
+
+    /// Like P4, but the first tail publish is relaxed.
+    pub fn p5() -> Program {
+        return ring(
+            [
+                (DATA0, 1, Rlx),
+                (TAIL, 1, Rlx),
+                (DATA1, 2, Rlx),
+                (TAIL, 2, Rel),
+            ],
+            Acq,
+            Rlx,
+            Rlx,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::programs::*;
+    use super::*;
+
+    #[test]
+    fn verdicts_match_verify_rkt() {
+        for (name, program, safe) in [
+            ("P1", p1(), true),
+            ("P2", p2(), false),
+            ("P3", p3(), true),
+            ("P4", p4(), true),
+            ("P5", p5(), false),
+        ] {
+            assert!(
+                !program.executions().is_empty(),
+                "{} has no execution",
+                name
+            );
+            let found = check(&program, &EXPECT);
+            assert_eq!(found.is_none(), safe, "{}: {:?}", name, found);
+        }
+
+        // P5 loses only the first slot: the release on the second tail covers DATA1
+        let program = p5();
+        let cex = check(&program, &EXPECT).unwrap();
+        assert_eq!(
+            cex.violations,
+            vec![Violation::StaleData { round: 0, seen: 0 }]
+        );
+    }
+
+    #[test]
+    fn relaxed_second_publish_misses_overwrite() {
+        // one-slot ring: the producer waits for the consumer to free the slot, then
+        // overwrites DATA0, but publishes the second tail relaxed
+        let program = Program::new(vec![
+            Event::rdma_write(1, 1, DATA0, 1, MemOrder::Rlx),
+            Event::rdma_write(2, 1, TAIL, 1, MemOrder::Rel),
+            Event::read(3, 1, HEAD, MemOrder::Acq),
+            Event::rdma_write(4, 1, DATA0, 2, MemOrder::Rlx),
+            Event::rdma_write(5, 1, TAIL, 2, MemOrder::Rlx),
+            Event::read(6, 2, TAIL, MemOrder::Acq),
+            Event::read(7, 2, DATA0, MemOrder::Rlx),
+            Event::write(8, 2, HEAD, 1, MemOrder::Rel),
+            Event::read(9, 2, TAIL, MemOrder::Acq),
+            Event::read(10, 2, DATA0, MemOrder::Rlx),
+        ]);
+        // reads: producer head, then consumer tail, data, tail, data
+        let expect = [
+            Expect {
+                tail_read: 1,
+                data_read: 2,
+                tail: 1,
+                data: 1,
+            },
+            Expect {
+                tail_read: 3,
+                data_read: 4,
+                tail: 2,
+                data: 2,
+            },
+        ];
+        let slot_freed = [(0, 1)];
+        let cex = check_assuming(&program, &expect, &slot_freed).unwrap();
+        assert!(
+            cex.violations
+                .contains(&Violation::MissedOverwrite { round: 1 })
+        );
+
+        // releasing the second tail fixes it
+        let mut fixed = program.clone();
+        fixed.events.iter_mut().find(|e| e.id == 5).unwrap().order = MemOrder::Rel;
+        assert!(check_assuming(&fixed, &expect, &slot_freed).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+#![allow(dead_code, clippy::needless_return)]
+#[cfg(target_os = "linux")]
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+};
+
+/// Told when the other side of a ring may have become ready, e.g. to kick an
+/// event loop. Called from the peer's thread.
+pub trait Notify: Send + Sync {
+    fn notify(&self);
+}
+
+/// Signals a Linux `eventfd`, so a ring can sit in epoll or mio next to sockets.
+/// The fd is non-blocking; the loop calls `drain` once it wakes.
+#[cfg(target_os = "linux")]
+pub struct EventFdNotifier {
+    fd: OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl EventFdNotifier {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        });
+    }
+
+    /// Reset the counter, returning how many notifications it had collected.
+    pub fn drain(&self) -> io::Result<u64> {
+        let mut count = 0u64;
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), (&raw mut count).cast(), 8) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        return Ok(count);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Notify for EventFdNotifier {
+    fn notify(&self) {
+        let one = 1u64;
+        // only fails if the counter would overflow, and then the fd is readable anyway
+        unsafe { libc::write(self.fd.as_raw_fd(), (&raw const one).cast(), 8) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsRawFd for EventFdNotifier {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.fd.as_raw_fd();
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsFd for EventFdNotifier {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        return self.fd.as_fd();
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::ring_buffer::RingBuf;
+    use std::sync::Arc;
+    use std::thread;
+
+    struct Epoll(OwnedFd);
+
+    impl Epoll {
+        fn new(fd: &impl AsRawFd) -> Self {
+            let ep = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            assert!(ep >= 0);
+            let mut ev = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: 7,
+            };
+            let rc = unsafe { libc::epoll_ctl(ep, libc::EPOLL_CTL_ADD, fd.as_raw_fd(), &mut ev) };
+            assert_eq!(rc, 0);
+            return Self(unsafe { OwnedFd::from_raw_fd(ep) });
+        }
+
+        fn wait(&self, timeout_ms: i32) -> usize {
+            let mut events = [libc::epoll_event { events: 0, u64: 0 }; 4];
+            let n =
+                unsafe { libc::epoll_wait(self.0.as_raw_fd(), events.as_mut_ptr(), 4, timeout_ms) };
+            assert!(n >= 0);
+            return n as usize;
+        }
+    }
+
+    #[test]
+    fn eventfd_counts_and_drains() {
+        let efd = EventFdNotifier::new().unwrap();
+        assert_eq!(efd.drain().unwrap(), 0);
+        efd.notify();
+        efd.notify();
+        assert_eq!(efd.drain().unwrap(), 2);
+        assert_eq!(efd.drain().unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "epoll is not modelled")]
+    fn epoll_wakes_consumer_on_produce() {
+        let (mut producer, consumer) = RingBuf::new(2).split();
+        let efd = Arc::new(EventFdNotifier::new().unwrap());
+        assert!(consumer.set_notifier(efd.clone()));
+        let epoll = Epoll::new(&*efd);
+
+        let writer = thread::spawn(move || {
+            for chunk in [[1u8; 4], [2u8; 4]] {
+                thread::sleep(std::time::Duration::from_millis(5));
+                producer.write(&chunk).unwrap();
+            }
+            // dropping the producer closes it, which notifies too
+        });
+
+        let mut got = Vec::new();
+        let mut buf = [0u8; 8];
+        loop {
+            let (_, n) = consumer.try_read(&mut buf).unwrap();
+            got.extend_from_slice(&buf[..n]);
+            if n > 0 {
+                continue;
+            }
+            if consumer.is_eof() {
+                break;
+            }
+            if !consumer.arm() {
+                assert_eq!(epoll.wait(5000), 1);
+                efd.drain().unwrap();
+            }
+            consumer.disarm();
+        }
+        writer.join().unwrap();
+        assert_eq!(got, [1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "epoll is not modelled")]
+    fn epoll_wakes_producer_when_space_frees() {
+        let (mut producer, consumer) = RingBuf::new(2).split();
+        let efd = Arc::new(EventFdNotifier::new().unwrap());
+        assert!(producer.set_notifier(efd.clone()));
+        let epoll = Epoll::new(&*efd);
+
+        assert_eq!(producer.try_write(&[0u8; 16]).unwrap().1, 16);
+        assert!(!producer.arm());
+        assert_eq!(epoll.wait(0), 0);
+
+        let reader = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(5));
+            consumer.try_read(&mut [0u8; 4]).unwrap();
+            consumer
+        });
+        assert_eq!(epoll.wait(5000), 1);
+        producer.disarm();
+        assert_eq!(efd.drain().unwrap(), 1);
+        assert_eq!(producer.try_write(&[0u8; 8]).unwrap().1, 4);
+        drop(reader.join().unwrap());
+    }
+}
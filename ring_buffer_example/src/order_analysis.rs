@@ -0,0 +1,498 @@
+//! Memory-order minimality analysis for `RingBuf`, built on loom.
+//!
+//! `PolicyRing` is the zero-copy and `write_full` paths of `RingBuf` with every
+//! atomic access taking its ordering from an `OrderingPolicy`. The analyzer
+//! starts from the orderings `RingBuf` uses today, weakens one site at a time
+//! and model-checks each candidate: a site whose every weakening fails is
+//! necessary, anything else can be relaxed to the weakest ordering that passed.
+//!
+//! Run with `RUSTFLAGS="--cfg loom" cargo test --release order_analysis -- --nocapture`.
+//!
+//! A third harness runs the zero-copy path with the consumer publishing `head`
+//! lazily, as a `Consumer` with `Batch::ring_fraction` does, so the same
+//! orderings are checked against a consumer that hands space back several
+//! bytes at a time.
+//!
+//! Only publication is modelled: the harnesses catch unsynchronized access to
+//! ring bytes (loom's causality check) and stale data. The empty/full triggers
+//! are not checked, since loom treats SeqCst loads and stores as AcqRel and
+//! cannot tell a store-buffering race from a real one; the handles rely on
+//! `fence(SeqCst)` around their waiting flags for wakeups instead.
+#![allow(dead_code, clippy::needless_return)]
+use loom::cell::UnsafeCell;
+use loom::sync::Arc;
+use loom::sync::atomic::AtomicU32;
+use loom::thread;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+
+/// Every atomic access on the paths under analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Site {
+    SpaceHead,
+    SpaceTail,
+    ProduceTail,
+    ProduceStore,
+    ProduceHead,
+    DataHead,
+    DataTail,
+    ConsumeHead,
+    ConsumeStore,
+    ConsumeTail,
+    WriteFullHead,
+    WriteFullTail,
+    WriteFullStore,
+}
+
+impl Site {
+    pub const ALL: [Site; 13] = [
+        Site::SpaceHead,
+        Site::SpaceTail,
+        Site::ProduceTail,
+        Site::ProduceStore,
+        Site::ProduceHead,
+        Site::DataHead,
+        Site::DataTail,
+        Site::ConsumeHead,
+        Site::ConsumeStore,
+        Site::ConsumeTail,
+        Site::WriteFullHead,
+        Site::WriteFullTail,
+        Site::WriteFullStore,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Site::SpaceHead => "get_space_buf: head load",
+            Site::SpaceTail => "get_space_buf: tail load",
+            Site::ProduceTail => "produce: tail load",
+            Site::ProduceStore => "produce: tail store",
+            Site::ProduceHead => "produce: head load",
+            Site::DataHead => "get_data_buf: head load",
+            Site::DataTail => "get_data_buf: tail load",
+            Site::ConsumeHead => "consume: head load",
+            Site::ConsumeStore => "consume: head store",
+            Site::ConsumeTail => "consume: tail load",
+            Site::WriteFullHead => "write_full: head load",
+            Site::WriteFullTail => "write_full: tail load",
+            Site::WriteFullStore => "write_full: tail store",
+        }
+    }
+
+    pub fn is_store(self) -> bool {
+        return matches!(
+            self,
+            Site::ProduceStore | Site::ConsumeStore | Site::WriteFullStore
+        );
+    }
+
+    /// Orderings valid for this site, strongest first.
+    pub fn candidates(self) -> &'static [Ordering] {
+        if self.is_store() {
+            return &[Ordering::SeqCst, Ordering::Release, Ordering::Relaxed];
+        }
+        return &[Ordering::SeqCst, Ordering::Acquire, Ordering::Relaxed];
+    }
+
+    fn index(self) -> usize {
+        return Site::ALL.iter().position(|s| *s == self).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderingPolicy([Ordering; Site::ALL.len()]);
+
+impl OrderingPolicy {
+    /// The orderings `RingBuf` uses today.
+    pub fn baseline() -> Self {
+        let mut policy = Self::seq_cst();
+        for site in Site::ALL {
+            let ord = match site {
+                Site::SpaceHead | Site::ProduceHead | Site::ConsumeTail => Ordering::Acquire,
+                Site::SpaceTail | Site::ProduceTail | Site::ConsumeHead => Ordering::Relaxed,
+                Site::ProduceStore | Site::ConsumeStore | Site::WriteFullStore => Ordering::Release,
+                Site::DataHead | Site::DataTail | Site::WriteFullHead | Site::WriteFullTail => {
+                    Ordering::SeqCst
+                }
+            };
+            policy = policy.with(site, ord);
+        }
+        return policy;
+    }
+
+    pub fn seq_cst() -> Self {
+        return Self([Ordering::SeqCst; Site::ALL.len()]);
+    }
+
+    pub fn get(&self, site: Site) -> Ordering {
+        return self.0[site.index()];
+    }
+
+    pub fn with(mut self, site: Site, ord: Ordering) -> Self {
+        self.0[site.index()] = ord;
+        return self;
+    }
+}
+
+/// `RingBuf` reduced to positions instead of addresses, over loom primitives.
+pub struct PolicyRing {
+    buf: Vec<UnsafeCell<u8>>,
+    ring_mask: u32,
+    head: AtomicU32,
+    tail: AtomicU32,
+    /// Lazy head: bytes consumed but not published, and when to publish them.
+    pending: AtomicU32,
+    threshold: u32,
+    policy: OrderingPolicy,
+}
+
+impl PolicyRing {
+    pub fn new(len: usize, policy: OrderingPolicy) -> Self {
+        assert!(len.is_power_of_two());
+        return Self {
+            buf: (0..len).map(|_| UnsafeCell::new(0)).collect(),
+            ring_mask: len as u32 - 1,
+            head: AtomicU32::new(0),
+            tail: AtomicU32::new(0),
+            pending: AtomicU32::new(0),
+            threshold: 0,
+            policy,
+        };
+    }
+
+    /// Publish `head` only every `threshold` bytes or when the ring looked
+    /// full, like `Consumer::commit` under a byte `Batch`.
+    pub fn with_lazy_head(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+        return self;
+    }
+
+    fn ord(&self, site: Site) -> Ordering {
+        return self.policy.get(site);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.buf.len();
+    }
+
+    pub fn write_byte(&self, pos: usize, val: u8) {
+        self.buf[pos].with_mut(|p| unsafe { *p = val });
+    }
+
+    pub fn read_byte(&self, pos: usize) -> u8 {
+        return self.buf[pos].with(|p| unsafe { *p });
+    }
+
+    pub fn get_space_buf(&self) -> (usize, usize) {
+        let head = self.head.load(self.ord(Site::SpaceHead));
+        let tail = self.tail.load(self.ord(Site::SpaceTail));
+
+        let available = tail.wrapping_sub(head) as usize;
+        let write_pos = (tail & self.ring_mask) as usize;
+        let write_size = self.len() - available;
+        return (write_pos, write_size.min(self.len() - write_pos));
+    }
+
+    pub fn produce(&self, count: usize) -> bool {
+        let tail = self.tail.load(self.ord(Site::ProduceTail));
+        self.tail.store(
+            tail.wrapping_add(count as u32),
+            self.ord(Site::ProduceStore),
+        );
+        let head = self.head.load(self.ord(Site::ProduceHead));
+        return tail.wrapping_sub(head) == 0;
+    }
+
+    pub fn get_data_buf(&self) -> (usize, usize) {
+        let head = self
+            .head
+            .load(self.ord(Site::DataHead))
+            .wrapping_add(self.pending.load(Ordering::Relaxed));
+        let tail = self.tail.load(self.ord(Site::DataTail));
+
+        let available = tail.wrapping_sub(head) as usize;
+        let read_pos = (head & self.ring_mask) as usize;
+        return (read_pos, available.min(self.len() - read_pos));
+    }
+
+    pub fn consume(&self, count: usize) -> bool {
+        if self.threshold != 0 {
+            return self.consume_lazy(count as u32);
+        }
+        let head = self.head.load(self.ord(Site::ConsumeHead));
+        self.head.store(
+            head.wrapping_add(count as u32),
+            self.ord(Site::ConsumeStore),
+        );
+        let tail = self.tail.load(self.ord(Site::ConsumeTail));
+        return tail.wrapping_sub(head) as usize == self.len();
+    }
+
+    /// `consume` as `Consumer::commit` then `flush` do it under a byte `Batch`.
+    fn consume_lazy(&self, count: u32) -> bool {
+        let pending = self.pending.load(Ordering::Relaxed) + count;
+        let head = self.head.load(self.ord(Site::ConsumeHead));
+        let tail = self.tail.load(self.ord(Site::ConsumeTail));
+        let full = tail.wrapping_sub(head) as usize == self.len();
+        if pending < self.threshold && !full {
+            self.pending.store(pending, Ordering::Relaxed);
+            return false;
+        }
+        self.pending.store(0, Ordering::Relaxed);
+        self.head
+            .store(head.wrapping_add(pending), self.ord(Site::ConsumeStore));
+        return full;
+    }
+
+    /// Same shape as `RingBuf::write_full`: all of `buf` or nothing.
+    pub fn write_full(&self, buf: &[u8]) -> bool {
+        let head = self.head.load(self.ord(Site::WriteFullHead));
+        let tail = self.tail.load(self.ord(Site::WriteFullTail));
+        let space = self.len() - tail.wrapping_sub(head) as usize;
+        if space < buf.len() {
+            return false;
+        }
+        for (i, b) in buf.iter().enumerate() {
+            self.write_byte((tail as usize + i) & self.ring_mask as usize, *b);
+        }
+        self.tail.store(
+            tail.wrapping_add(buf.len() as u32),
+            self.ord(Site::WriteFullStore),
+        );
+        return true;
+    }
+}
+
+// Two bytes of ring and three items, so the consumer drains a slot the producer
+// then reuses; this exercises both the data and the space hand-off.
+const RING_LEN: usize = 2;
+const ITEMS: u8 = 3;
+
+fn consume_all(ring: &PolicyRing) {
+    let mut next = 1;
+    while next <= ITEMS {
+        let (pos, len) = ring.get_data_buf();
+        if len == 0 {
+            thread::yield_now();
+            continue;
+        }
+        assert_eq!(ring.read_byte(pos), next, "stale data");
+        ring.consume(1);
+        next += 1;
+    }
+}
+
+/// Producer publishes through `get_space_buf` + `produce`.
+fn zero_copy_harness(policy: OrderingPolicy) {
+    zero_copy(PolicyRing::new(RING_LEN, policy));
+}
+
+/// As `zero_copy_harness`, with the consumer publishing only once the whole
+/// ring has been consumed or it looked full.
+fn lazy_head_harness(policy: OrderingPolicy) {
+    zero_copy(PolicyRing::new(RING_LEN, policy).with_lazy_head(RING_LEN as u32));
+}
+
+fn zero_copy(ring: PolicyRing) {
+    let ring = Arc::new(ring);
+    let producer = ring.clone();
+    let writer = thread::spawn(move || {
+        for item in 1..=ITEMS {
+            loop {
+                let (pos, len) = producer.get_space_buf();
+                if len > 0 {
+                    producer.write_byte(pos, item);
+                    producer.produce(1);
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+    });
+    consume_all(&ring);
+    writer.join().unwrap();
+}
+
+/// Producer publishes through `write_full`.
+fn copy_harness(policy: OrderingPolicy) {
+    let ring = Arc::new(PolicyRing::new(RING_LEN, policy));
+    let producer = ring.clone();
+    let writer = thread::spawn(move || {
+        for item in 1..=ITEMS {
+            while !producer.write_full(&[item]) {
+                thread::yield_now();
+            }
+        }
+    });
+    consume_all(&ring);
+    writer.join().unwrap();
+}
+
+const HARNESSES: [fn(OrderingPolicy); 3] = [zero_copy_harness, copy_harness, lazy_head_harness];
+
+/// Model-check every harness under `policy`. Failures surface as loom panics,
+/// which are caught and reported as `false`.
+pub fn policy_holds(policy: OrderingPolicy) -> bool {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let holds = HARNESSES.iter().all(|harness| {
+        let harness = *harness;
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            builder.check(move || harness(policy));
+        }))
+        .is_ok()
+    });
+    panic::set_hook(hook);
+    return holds;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SiteReport {
+    pub site: Site,
+    pub current: Ordering,
+    pub weakest: Ordering,
+}
+
+impl SiteReport {
+    /// No ordering weaker than the current one passed.
+    pub fn is_necessary(&self) -> bool {
+        return self.current != Ordering::Relaxed && self.weakest == self.current;
+    }
+}
+
+impl fmt::Display for SiteReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verdict = if self.is_necessary() {
+            "necessary".to_string()
+        } else if self.current == Ordering::Relaxed {
+            "already relaxed".to_string()
+        } else {
+            format!("can relax to {:?}", self.weakest)
+        };
+        write!(
+            f,
+            "{:<24} {:<8} {}",
+            self.site.name(),
+            format!("{:?}", self.current),
+            verdict
+        )
+    }
+}
+
+/// Weaken each site of `base` on its own, keeping the rest as they are.
+pub fn analyze(base: OrderingPolicy) -> Vec<SiteReport> {
+    assert!(policy_holds(base), "baseline policy fails its own harness");
+    return Site::ALL
+        .iter()
+        .map(|&site| {
+            let current = base.get(site);
+            let weaker = site
+                .candidates()
+                .iter()
+                .skip_while(|ord| **ord != current)
+                .skip(1);
+            let mut weakest = current;
+            for &ord in weaker {
+                if policy_holds(base.with(site, ord)) {
+                    weakest = ord;
+                }
+            }
+            SiteReport {
+                site,
+                current,
+                weakest,
+            }
+        })
+        .collect();
+}
+
+/// Apply every relaxation from `reports` at once. Relaxations found one site
+/// at a time need not compose, so the result is checked again by the caller.
+pub fn minimal_policy(base: OrderingPolicy, reports: &[SiteReport]) -> OrderingPolicy {
+    return reports
+        .iter()
+        .fold(base, |policy, r| policy.with(r.site, r.weakest));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline_orderings_are_minimal_where_it_matters() {
+        let base = OrderingPolicy::baseline();
+        let reports = analyze(base);
+        for report in &reports {
+            println!("{}", report);
+        }
+
+        // the publishing stores and the loads that read the peer's index carry the
+        // hand-off of ring bytes and cannot be weakened
+        for site in [Site::ProduceStore, Site::ConsumeStore, Site::WriteFullStore] {
+            assert_eq!(
+                reports[site.index()].weakest,
+                Ordering::Release,
+                "{:?}",
+                site
+            );
+        }
+        for site in [Site::SpaceHead, Site::DataTail] {
+            assert_eq!(
+                reports[site.index()].weakest,
+                Ordering::Acquire,
+                "{:?}",
+                site
+            );
+        }
+        // loads of a side's own index, and loads only used for triggers, need nothing
+        for site in [
+            Site::DataHead,
+            Site::ConsumeTail,
+            Site::ProduceHead,
+            Site::WriteFullTail,
+        ] {
+            assert_eq!(
+                reports[site.index()].weakest,
+                Ordering::Relaxed,
+                "{:?}",
+                site
+            );
+        }
+
+        let minimal = minimal_policy(base, &reports);
+        println!("minimal policy: {:?}", minimal);
+        assert!(policy_holds(minimal));
+    }
+
+    #[test]
+    fn lazy_head_needs_the_same_release() {
+        let base = OrderingPolicy::baseline();
+        let check = |policy| {
+            let mut builder = loom::model::Builder::new();
+            builder.preemption_bound = Some(3);
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                builder.check(move || lazy_head_harness(policy));
+            }));
+            return res.is_ok();
+        };
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let holds = check(base);
+        let relaxed_holds = check(base.with(Site::ConsumeStore, Ordering::Relaxed));
+        panic::set_hook(hook);
+        assert!(holds);
+        assert!(!relaxed_holds);
+    }
+
+    #[test]
+    fn fully_relaxed_ring_is_caught() {
+        let relaxed = Site::ALL.iter().fold(OrderingPolicy::seq_cst(), |p, &s| {
+            p.with(s, Ordering::Relaxed)
+        });
+        assert!(policy_holds(OrderingPolicy::seq_cst()));
+        assert!(!policy_holds(relaxed));
+    }
+}
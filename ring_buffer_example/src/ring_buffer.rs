@@ -1,25 +1,29 @@
-#![allow(dead_code)]
+#![allow(dead_code, clippy::needless_return)]
+pub use crate::error::{Error, Result};
+use crate::notify::Notify;
+use crate::storage::{Backing, Storage};
 use std::{
-    cell::UnsafeCell,
-    fmt, slice, str,
+    fmt, ptr, slice,
     sync::{
-        Arc,
-        atomic::{AtomicU32, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering},
     },
 };
 
-#[derive(Debug)]
-pub enum SysErr {
-    EINVAL,
-}
+#[cfg(feature = "stats")]
+use crate::stats::{OpStats, RingCounters};
 
-#[derive(Debug)]
-pub enum Error {
-    SysError(SysErr),
-    QueueFull,
+/// Run a counter update only when the `stats` feature is enabled; otherwise the
+/// arguments are not even evaluated.
+macro_rules! stat {
+    ($ring:expr, $side:ident . $($call:tt)*) => {
+        #[cfg(feature = "stats")]
+        $ring.counters.$side.$($call)*;
+    };
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
+/// Header in front of every chunk of a `RingMode::Stamped` ring.
+pub const STAMP: usize = 8;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Iov {
@@ -32,12 +36,95 @@ pub struct SocketBufIovs<'a> {
     pub cnt: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingMode {
+    /// `write` stops at a full ring and only the consumer ever moves `head`.
+    Blocking,
+    /// `write` always succeeds by dropping the oldest bytes: the producer may
+    /// push `head` forward, and the consumer must claim data with a CAS on
+    /// `head` (see `read_lossy`) to find out whether it was overrun. The
+    /// zero-copy calls would hand out memory the producer may overwrite, so the
+    /// ones returning a `Result` fail with `Unsupported`, `get_data_buf` and
+    /// `get_space_buf` hand out nothing, and `produce`/`consume` do nothing.
+    Overwrite,
+    /// Like `Blocking`, but every publication is a chunk stamped with the
+    /// `tail` it was published under, which `read` and `get_data_buf` check
+    /// (see `check_stamp`). Only `write`/`read` and
+    /// `get_space_buf`/`produce`/`get_data_buf`/`consume` know about stamps.
+    Stamped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Producer,
+    Consumer,
+}
+
+/// Bookkeeping kept only for hang diagnostics. Each field is written by a single
+/// side with relaxed stores, so it never takes part in head/tail synchronization.
+#[derive(Default)]
+pub struct RingDiag {
+    pub producer_seen_head: AtomicU32,
+    pub consumer_seen_tail: AtomicU32,
+    pub produce_trigger: AtomicBool,
+    pub consume_trigger: AtomicBool,
+    pub producer_parked: AtomicBool,
+    pub consumer_parked: AtomicBool,
+    /// Stamped mode: chunks `get_data_buf` turned away as stale.
+    pub stale_chunks: AtomicU32,
+}
+
+pub const PRODUCER_CLOSED: u32 = 1;
+pub const CONSUMER_CLOSED: u32 = 2;
+
+/// Shutdown flags and the wait queue behind the blocking `Producer`/`Consumer`
+/// calls. A side sets its `*_waiting` flag before its final readiness check and
+/// the peer checks it after publishing, both separated by SeqCst fences, so a
+/// wakeup cannot slip between the check and the wait.
+#[derive(Default)]
+pub struct RingState {
+    pub closed: AtomicU32,
+    pub producer_waiting: AtomicBool,
+    pub consumer_waiting: AtomicBool,
+    pub lock: Mutex<()>,
+    pub cond: Condvar,
+    /// Event-loop hooks for each side, see `Producer::set_notifier`.
+    pub producer_notify: OnceLock<Arc<dyn Notify>>,
+    pub consumer_notify: OnceLock<Arc<dyn Notify>>,
+}
+
+/// Point-in-time view of a ring, as printed by `fmt::Debug` and the watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingStats {
+    pub head: u32,
+    pub tail: u32,
+    pub len: usize,
+    pub available_data: usize,
+    pub available_space: usize,
+    pub producer_seen_head: u32,
+    pub consumer_seen_tail: u32,
+    pub produce_trigger: bool,
+    pub consume_trigger: bool,
+    pub producer_parked: bool,
+    pub consumer_parked: bool,
+    pub stale_chunks: u32,
+    #[cfg(feature = "stats")]
+    pub ops: OpStats,
+}
+
 #[derive(Clone)]
 pub struct RingBuf {
-    pub buf: Arc<Vec<UnsafeCell<u64>>>,
+    pub buf: Arc<Storage>,
     pub ring_mask: u32,
     pub head: Arc<AtomicU32>,
     pub tail: Arc<AtomicU32>,
+    pub mode: RingMode,
+    /// Overwrite mode only: the `head` value the consumer last claimed.
+    pub read_seq: Arc<AtomicU32>,
+    pub diag: Arc<RingDiag>,
+    pub state: Arc<RingState>,
+    #[cfg(feature = "stats")]
+    pub counters: Arc<RingCounters>,
 }
 
 unsafe impl Send for RingBuf {}
@@ -45,30 +132,51 @@ unsafe impl Sync for RingBuf {}
 
 impl fmt::Debug for RingBuf {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "RingBuf buf {:?} head/tail {:x?}/{:x?}",
-            *self.buf, self.head, self.tail
-        )
+        write!(f, "RingBuf {:x?}", self.stats())
     }
 }
 
 impl RingBuf {
     pub fn is_power_of_two(x: usize) -> bool {
-        return (x & (x - 1)) == 0;
+        return x != 0 && (x & (x - 1)) == 0;
     }
 
     pub fn new(count: usize) -> Self {
+        return Self::with_mode(count, RingMode::Blocking);
+    }
+
+    pub fn with_mode(count: usize, mode: RingMode) -> Self {
+        return Self::from_storage(Storage::heap(count), mode);
+    }
+
+    pub fn with_backing(count: usize, backing: Backing) -> Result<Self> {
+        if !Self::is_power_of_two(count * 8) {
+            return Err(Error::InvalidCapacity { len: count * 8 });
+        }
+        let storage =
+            Storage::new(count, backing).map_err(|err| Error::from_setup(err, count * 8))?;
+        return Ok(Self::from_storage(storage, RingMode::Blocking));
+    }
+
+    // the ring itself is Send and Sync through the unsafe impls above
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn from_storage(storage: Storage, mode: RingMode) -> Self {
         return Self {
-            buf: Arc::new((0..count).map(|_| UnsafeCell::new(0)).collect()),
-            ring_mask: ((count * 8) - 1) as u32,
+            ring_mask: (storage.len() - 1) as u32,
+            buf: Arc::new(storage),
             head: Arc::new(AtomicU32::new(0)),
             tail: Arc::new(AtomicU32::new(0)),
+            mode,
+            read_seq: Arc::new(AtomicU32::new(0)),
+            diag: Arc::new(RingDiag::default()),
+            state: Arc::new(RingState::default()),
+            #[cfg(feature = "stats")]
+            counters: Arc::new(RingCounters::default()),
         };
     }
 
     //return (bufAddr, bufSize)
-    pub fn get_raw_buf(&self) -> (&Vec<UnsafeCell<u64>>, usize) {
+    pub fn get_raw_buf(&self) -> (&Storage, usize) {
         return (&self.buf, self.len());
     }
 
@@ -77,11 +185,60 @@ impl RingBuf {
         return (self.ring_mask + 1) as usize;
     }
 
+    /// Base address of the ring storage. Bytes behind it may be written through
+    /// this pointer only by the side that currently owns them under the
+    /// head/tail protocol; never turn it into a `&mut [u8]` over the whole ring.
+    #[inline]
+    pub fn buf_ptr(&self) -> *mut u8 {
+        return self.buf.as_ptr();
+    }
+
+    // copy ring bytes [pos, pos + dst.len()) out into dst
+    // Safety: the range must lie inside the storage and be readable by the caller's side.
+    #[inline]
+    unsafe fn read_raw(&self, pos: usize, dst: &mut [u8]) {
+        debug_assert!(pos + dst.len() <= self.buf.mapped_len());
+        unsafe { ptr::copy_nonoverlapping(self.buf_ptr().add(pos), dst.as_mut_ptr(), dst.len()) };
+    }
+
+    // copy src into ring bytes [pos, pos + src.len())
+    // Safety: the range must lie inside the storage and be owned by the producer side.
+    #[inline]
+    unsafe fn write_raw(&self, pos: usize, src: &[u8]) {
+        debug_assert!(pos + src.len() <= self.buf.mapped_len());
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr(), self.buf_ptr().add(pos), src.len()) };
+    }
+
+    // Overwrite mode: the producer may rewrite bytes while the consumer copies
+    // them out, so both sides go through byte-wide relaxed atomics and the
+    // consumer's CAS on head decides afterwards whether the copy counts.
+    // Safety: the range must lie inside the storage.
+    #[inline]
+    unsafe fn read_racy(&self, pos: usize, dst: &mut [u8]) {
+        debug_assert!(pos + dst.len() <= self.buf.mapped_len());
+        for (i, b) in dst.iter_mut().enumerate() {
+            let byte = unsafe { AtomicU8::from_ptr(self.buf_ptr().add(pos + i)) };
+            *b = byte.load(Ordering::Relaxed);
+        }
+    }
+
+    // Safety: the range must lie inside the storage.
+    #[inline]
+    unsafe fn write_racy(&self, pos: usize, src: &[u8]) {
+        debug_assert!(pos + src.len() <= self.buf.mapped_len());
+        for (i, b) in src.iter().enumerate() {
+            let byte = unsafe { AtomicU8::from_ptr(self.buf_ptr().add(pos + i)) };
+            byte.store(*b, Ordering::Relaxed);
+        }
+    }
+
+    // how many bytes starting at ring offset pos can be addressed without wrapping
     #[inline]
-    pub fn buf(&self) -> &mut [u8] {
-        let ptr = self.buf.as_ptr() as *mut u8;
-        let len = self.buf.len() * 8;
-        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    pub(crate) fn to_end(&self, pos: usize) -> usize {
+        if self.buf.is_mirrored() {
+            return self.len();
+        }
+        return self.len() - pos;
     }
 
     pub fn available_data_size(&self) -> usize {
@@ -94,11 +251,81 @@ impl RingBuf {
         return self.len() - self.available_data_size();
     }
 
+    pub fn stats(&self) -> RingStats {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        let available = tail.wrapping_sub(head) as usize;
+        return RingStats {
+            head,
+            tail,
+            len: self.len(),
+            available_data: available,
+            available_space: self.len().saturating_sub(available),
+            producer_seen_head: self.diag.producer_seen_head.load(Ordering::Relaxed),
+            consumer_seen_tail: self.diag.consumer_seen_tail.load(Ordering::Relaxed),
+            produce_trigger: self.diag.produce_trigger.load(Ordering::Relaxed),
+            consume_trigger: self.diag.consume_trigger.load(Ordering::Relaxed),
+            producer_parked: self.diag.producer_parked.load(Ordering::Relaxed),
+            consumer_parked: self.diag.consumer_parked.load(Ordering::Relaxed),
+            stale_chunks: self.diag.stale_chunks.load(Ordering::Relaxed),
+            #[cfg(feature = "stats")]
+            ops: self.counters.snapshot(),
+        };
+    }
+
+    /// Mark a side as blocked (or no longer blocked) waiting for its peer.
+    pub fn set_parked(&self, side: Side, parked: bool) {
+        match side {
+            Side::Producer => self.diag.producer_parked.store(parked, Ordering::Relaxed),
+            Side::Consumer => self.diag.consumer_parked.store(parked, Ordering::Relaxed),
+        }
+    }
+
+    #[inline]
+    fn note_head(&self, head: u32) {
+        self.diag.producer_seen_head.store(head, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn note_tail(&self, tail: u32) {
+        self.diag.consumer_seen_tail.store(tail, Ordering::Relaxed);
+    }
+
+    // fail `op` with `Error::Unsupported` on a ring in `mode`
+    pub(crate) fn check_mode(&self, op: &'static str, mode: RingMode) -> Result<()> {
+        if self.mode == mode {
+            return Err(Error::Unsupported { op, mode });
+        }
+        return Ok(());
+    }
+
+    // fail `op` with `Error::Unsupported` on a ring in any mode but `mode`
+    fn require_mode(&self, op: &'static str, mode: RingMode) -> Result<()> {
+        if self.mode != mode {
+            return Err(Error::Unsupported {
+                op,
+                mode: self.mode,
+            });
+        }
+        return Ok(());
+    }
+
     /****************************************** read *********************************************************/
     //return (initial size is full, how much read)
     pub fn read(&self, buf: &mut [u8]) -> Result<(bool, usize)> {
-        let head = self.head.load(Ordering::Acquire);
-        let tail = self.tail.load(Ordering::Relaxed);
+        if self.mode == RingMode::Overwrite {
+            let (_lost, n) = self.read_lossy(buf)?;
+            return Ok((false, n));
+        }
+        if self.mode == RingMode::Stamped {
+            return self.read_stamped(buf);
+        }
+
+        let head = self.head.load(Ordering::Relaxed);
+        // Acquire pairs with the producer's Release store of tail, so the bytes
+        // copied below are the ones it wrote
+        let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
 
         let mut available = tail.wrapping_sub(head) as usize;
         let full = available == self.len();
@@ -109,7 +336,7 @@ impl RingBuf {
 
         let read_pos = (head & self.ring_mask) as usize;
         let (first_len, has_second) = {
-            let to_end = self.len() - read_pos;
+            let to_end = self.to_end(read_pos);
             if to_end < available {
                 (to_end, true)
             } else {
@@ -117,49 +344,135 @@ impl RingBuf {
             }
         };
 
-        buf[0..first_len].copy_from_slice(&self.buf()[read_pos..read_pos + first_len]);
+        unsafe { self.read_raw(read_pos, &mut buf[0..first_len]) };
 
         if has_second {
             let second_len = available - first_len;
-            buf[first_len..first_len + second_len].copy_from_slice(&self.buf()[0..second_len])
+            unsafe { self.read_raw(0, &mut buf[first_len..first_len + second_len]) };
         }
 
         self.head
             .store(head.wrapping_add(available as u32), Ordering::Release);
+        stat!(self, consumer.on_transfer(available, full));
+        if available == 0 {
+            stat!(self, consumer.on_zero_call());
+        }
         return Ok((full, available));
     }
 
-    pub fn read_via_addr(&self, buf: u64, count: u64) -> (bool, usize) {
+    /// Copy up to `buf.len()` bytes from the front of the ring without consuming them.
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        return self.peek_at(0, buf);
+    }
+
+    /// Like `peek`, but starting `offset` bytes past `head`. Returns 0 when no
+    /// more than `offset` bytes are available.
+    pub fn peek_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.check_mode("peek", RingMode::Overwrite)?;
+        self.check_mode("peek", RingMode::Stamped)?;
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
+
+        let available = tail.wrapping_sub(head) as usize;
+        if offset >= available {
+            return Ok(0);
+        }
+
+        let count = std::cmp::min(available - offset, buf.len());
+        self.copy_out(head.wrapping_add(offset as u32), &mut buf[..count]);
+        return Ok(count);
+    }
+
+    /// Drop up to `count` bytes from the front of the ring, return how many were dropped.
+    pub fn skip(&self, count: usize) -> Result<usize> {
+        self.check_mode("skip", RingMode::Overwrite)?;
+        self.check_mode("skip", RingMode::Stamped)?;
+        let count = std::cmp::min(count, self.available_data_size());
+        if count > 0 {
+            self.consume(count);
+        }
+        return Ok(count);
+    }
+
+    // copy buf.len() bytes starting at ring position pos, following the wrap
+    fn copy_out(&self, pos: u32, buf: &mut [u8]) {
+        let read_pos = (pos & self.ring_mask) as usize;
+        let first_len = std::cmp::min(buf.len(), self.to_end(read_pos));
+        unsafe { self.read_raw(read_pos, &mut buf[0..first_len]) };
+        if first_len < buf.len() {
+            unsafe { self.read_raw(0, &mut buf[first_len..]) };
+        }
+    }
+
+    // copy buf into ring position pos onwards, following the wrap
+    fn copy_in(&self, pos: u32, buf: &[u8]) {
+        let write_pos = (pos & self.ring_mask) as usize;
+        let first_len = std::cmp::min(buf.len(), self.to_end(write_pos));
+        unsafe { self.write_raw(write_pos, &buf[0..first_len]) };
+        if first_len < buf.len() {
+            unsafe { self.write_raw(0, &buf[first_len..]) };
+        }
+    }
+
+    /// `read` into a caller buffer given by address.
+    ///
+    /// # Safety
+    /// `buf` must point to `count` bytes that are valid for writes, are not part
+    /// of this ring, and are not accessed by anything else during the call.
+    pub unsafe fn read_via_addr(&self, buf: u64, count: u64) -> Result<(bool, usize)> {
         let ptr = buf as *mut u8;
         let slice = unsafe { slice::from_raw_parts_mut(ptr, count as usize) };
-        let res = self.read(slice).expect("read_via_addr get error");
-        return res;
+        return self.read(slice);
     }
 
     //return addr, len, whethere there is more space
-    pub fn get_read_buf(&self) -> Option<(u64, usize, bool)> {
+    pub fn get_read_buf(&self) -> Result<Option<(u64, usize, bool)>> {
+        self.check_mode("get_read_buf", RingMode::Overwrite)?;
+        self.check_mode("get_read_buf", RingMode::Stamped)?;
         let head = self.head.load(Ordering::Relaxed);
         let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
 
         let available = tail.wrapping_sub(head) as usize;
 
         if available == 0 {
-            return None;
+            return Ok(None);
         }
 
         let read_pos = (head & self.ring_mask) as usize;
-        let to_end = self.len() - read_pos;
+        let to_end = self.to_end(read_pos);
         if to_end < available {
-            return Some((self.buf.as_ptr() as u64 + read_pos as u64, to_end, true));
+            return Ok(Some((
+                self.buf_ptr() as u64 + read_pos as u64,
+                to_end,
+                true,
+            )));
         } else {
-            return Some((self.buf.as_ptr() as u64 + read_pos as u64, available, false));
+            return Ok(Some((
+                self.buf_ptr() as u64 + read_pos as u64,
+                available,
+                false,
+            )));
         }
     }
 
+    /// On a stamped ring a stale chunk reads as no data and is counted in
+    /// `RingStats::stale_chunks`; `get_stamped_buf` returns the error itself.
     pub fn get_data_buf(&self) -> (u64, usize) {
+        if self.mode == RingMode::Overwrite {
+            return (0, 0);
+        }
+        if self.mode == RingMode::Stamped {
+            return self.get_stamped_buf().unwrap_or_else(|_| {
+                self.diag.stale_chunks.fetch_add(1, Ordering::Relaxed);
+                return (0, 0);
+            });
+        }
         //TODO: Revisit memory order to loose constraints
         let head = self.head.load(Ordering::SeqCst);
         let tail = self.tail.load(Ordering::SeqCst);
+        self.note_tail(tail);
 
         let available = tail.wrapping_sub(head) as usize;
 
@@ -168,50 +481,59 @@ impl RingBuf {
         }
 
         let read_pos = (head & self.ring_mask) as usize;
-        let to_end = self.len() - read_pos;
+        let to_end = self.to_end(read_pos);
         if to_end < available {
-            return (self.buf.as_ptr() as u64 + read_pos as u64, to_end);
+            return (self.buf_ptr() as u64 + read_pos as u64, to_end);
         } else {
-            return (self.buf.as_ptr() as u64 + read_pos as u64, available);
+            return (self.buf_ptr() as u64 + read_pos as u64, available);
         }
     }
 
-    pub fn prepare_data_iovs(&self, data: &mut SocketBufIovs) {
+    pub fn prepare_data_iovs(&self, data: &mut SocketBufIovs) -> Result<()> {
+        self.check_mode("prepare_data_iovs", RingMode::Overwrite)?;
+        self.check_mode("prepare_data_iovs", RingMode::Stamped)?;
         let iovs = &mut data.iovs;
 
         let head = self.head.load(Ordering::Relaxed);
         let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
 
         let available = tail.wrapping_sub(head) as usize;
 
         if available == 0 {
             data.cnt = 0;
-            return;
+            return Ok(());
         }
 
         assert!(iovs.len() >= 2);
         let read_pos = (head & self.ring_mask) as usize;
-        let to_end = self.len() - read_pos;
+        let to_end = self.to_end(read_pos);
         if to_end < available {
-            iovs[0].start = &self.buf()[read_pos as usize] as *const _ as u64;
-            iovs[0].len = to_end as usize;
+            iovs[0].start = self.buf_ptr() as u64 + read_pos as u64;
+            iovs[0].len = to_end;
 
-            iovs[1].start = &self.buf()[0] as *const _ as u64;
+            iovs[1].start = self.buf_ptr() as u64;
             iovs[1].len = available - to_end;
 
             data.cnt = 2;
+            stat!(self, consumer.on_wrap());
         } else {
-            iovs[0].start = &self.buf()[read_pos as usize] as *const _ as u64;
-            iovs[0].len = available as usize;
+            iovs[0].start = self.buf_ptr() as u64 + read_pos as u64;
+            iovs[0].len = available;
 
             data.cnt = 1;
         }
+        return Ok(());
     }
 
     pub fn consume_with_check(&self, count: usize) -> Result<bool> {
+        self.check_mode("consume", RingMode::Overwrite)?;
         let available = self.available_data_size();
         if available < count {
-            return Err(Error::SysError(SysErr::EINVAL));
+            return Err(Error::InsufficientData {
+                requested: count,
+                available,
+            });
         }
 
         let trigger = self.consume(count);
@@ -220,6 +542,18 @@ impl RingBuf {
 
     //consume count data
     pub fn consume(&self, count: usize) -> bool {
+        // the lossy producer owns `head` as well; see `consume_with_check`
+        if self.mode == RingMode::Overwrite {
+            return false;
+        }
+        // the counters see payload bytes only, as `produce` does
+        #[cfg(feature = "stats")]
+        let payload = count;
+        let count = if self.mode == RingMode::Stamped {
+            STAMP + count
+        } else {
+            count
+        };
         //2
         //TODO: Revisit memory order to loose constraints
         let head = self.head.load(Ordering::Relaxed);
@@ -227,46 +561,61 @@ impl RingBuf {
             .store(head.wrapping_add(count as u32), Ordering::Release);
 
         let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
+
         let available = tail.wrapping_sub(head) as usize;
         let trigger = available == self.len();
+        self.diag.consume_trigger.store(trigger, Ordering::Relaxed);
+        stat!(self, consumer.on_transfer(payload, trigger));
         return trigger;
     }
     /****************************************** write *********************************************************/
 
-    pub fn get_write_buf(&self) -> Option<(u64, usize, bool)> {
+    pub fn get_write_buf(&self) -> Result<Option<(u64, usize, bool)>> {
+        self.check_mode("get_write_buf", RingMode::Overwrite)?;
+        self.check_mode("get_write_buf", RingMode::Stamped)?;
         let head = self.head.load(Ordering::Acquire);
+        self.note_head(head);
         let tail = self.tail.load(Ordering::Relaxed);
 
         let available = tail.wrapping_sub(head) as usize;
         if available == self.len() {
-            return None;
+            return Ok(None);
         }
 
         let write_pos = (tail & self.ring_mask) as usize;
         let write_size = self.len() - available;
 
-        let to_end = self.len() - write_pos;
+        let to_end = self.to_end(write_pos);
         if to_end < write_size {
-            return Some((self.buf.as_ptr() as u64 + write_pos as u64, to_end, true));
+            return Ok(Some((
+                self.buf_ptr() as u64 + write_pos as u64,
+                to_end,
+                true,
+            )));
         } else {
-            return Some((
-                self.buf.as_ptr() as u64 + write_pos as u64,
+            return Ok(Some((
+                self.buf_ptr() as u64 + write_pos as u64,
                 write_size,
                 false,
-            ));
+            )));
         }
     }
 
-    pub fn prepare_space_iovs(&self, data: &mut SocketBufIovs) {
+    pub fn prepare_space_iovs(&self, data: &mut SocketBufIovs) -> Result<()> {
+        self.check_mode("prepare_space_iovs", RingMode::Overwrite)?;
+        self.check_mode("prepare_space_iovs", RingMode::Stamped)?;
         let iovs = &mut data.iovs;
 
         let head = self.head.load(Ordering::Acquire);
+
+        self.note_head(head);
         let tail = self.tail.load(Ordering::Relaxed);
         let available = tail.wrapping_sub(head) as usize;
 
         if available == self.len() {
             data.cnt = 0;
-            return;
+            return Ok(());
         }
 
         //error!("GetSpaceIovs available is {}", self.available);
@@ -274,30 +623,46 @@ impl RingBuf {
         let write_pos = (tail & self.ring_mask) as usize;
         let write_size = self.len() - available;
 
-        let to_end = self.len() - write_pos;
+        let to_end = self.to_end(write_pos);
 
         //error!("GetSpaceIovs available is {}, toEnd is {}", self.available, toEnd);
         if to_end < write_size {
-            iovs[0].start = &self.buf()[write_pos as usize] as *const _ as u64;
-            iovs[0].len = to_end as usize;
+            iovs[0].start = self.buf_ptr() as u64 + write_pos as u64;
+            iovs[0].len = to_end;
 
-            iovs[1].start = &self.buf()[0] as *const _ as u64;
+            iovs[1].start = self.buf_ptr() as u64;
             iovs[1].len = write_size - to_end;
 
             data.cnt = 2;
+            stat!(self, producer.on_wrap());
         } else {
-            iovs[0].start = &self.buf()[write_pos as usize] as *const _ as u64;
-            iovs[0].len = write_size as usize;
+            iovs[0].start = self.buf_ptr() as u64 + write_pos as u64;
+            iovs[0].len = write_size;
 
             data.cnt = 1;
         }
+        return Ok(());
     }
 
     pub fn get_space_buf(&self) -> (u64, usize) {
+        if self.mode == RingMode::Overwrite {
+            return (0, 0);
+        }
         let head = self.head.load(Ordering::Acquire);
+        self.note_head(head);
         let tail = self.tail.load(Ordering::Relaxed);
 
         let available = tail.wrapping_sub(head) as usize;
+        if self.mode == RingMode::Stamped {
+            // leave room for the stamp; the payload itself never wraps
+            let space = self.len() - available;
+            if space <= STAMP {
+                return (0, 0);
+            }
+            let write_pos = (tail.wrapping_add(STAMP as u32) & self.ring_mask) as usize;
+            let write_size = (space - STAMP).min(self.to_end(write_pos));
+            return (self.buf_ptr() as u64 + write_pos as u64, write_size);
+        }
         if available == self.len() {
             return (0, 0);
         }
@@ -305,39 +670,73 @@ impl RingBuf {
         let write_pos = (tail & self.ring_mask) as usize;
         let write_size = self.len() - available;
 
-        let to_end = self.len() - write_pos;
+        let to_end = self.to_end(write_pos);
         if to_end < write_size {
-            return (self.buf.as_ptr() as u64 + write_pos as u64, to_end);
+            return (self.buf_ptr() as u64 + write_pos as u64, to_end);
         } else {
-            return (self.buf.as_ptr() as u64 + write_pos as u64, write_size);
+            return (self.buf_ptr() as u64 + write_pos as u64, write_size);
         }
     }
 
     pub fn produce_with_check(&self, count: usize) -> Result<bool> {
+        self.check_mode("produce", RingMode::Overwrite)?;
+        if count == 0 {
+            self.check_mode("an empty chunk", RingMode::Stamped)?;
+        }
         let available = self.available_data_size();
         if available + count > self.len() {
-            return Err(Error::SysError(SysErr::EINVAL));
+            return Err(Error::InsufficientSpace {
+                requested: count,
+                free: self.len() - available,
+            });
         }
 
         let trigger = self.produce(count);
         return Ok(trigger);
     }
 
+    /// On a stamped ring `produce(0)` publishes nothing: an empty chunk would
+    /// read as no data and stall the consumer in front of it. On an overwrite
+    /// ring nothing is ever published, see `produce_with_check`.
     pub fn produce(&self, count: usize) -> bool {
+        if self.mode == RingMode::Overwrite || (count == 0 && self.mode == RingMode::Stamped) {
+            return false;
+        }
         //TODO: Revisit memory order to loose constraints
         let tail = self.tail.load(Ordering::Relaxed);
-        self.tail
-            .store(tail.wrapping_add(count as u32), Ordering::Release);
+        let new_tail = if self.mode == RingMode::Stamped {
+            self.stamp(tail, count)
+        } else {
+            tail.wrapping_add(count as u32)
+        };
+        self.tail.store(new_tail, Ordering::Release);
 
         let head = self.head.load(Ordering::Acquire);
+
+        self.note_head(head);
         let available = tail.wrapping_sub(head) as usize;
         let trigger = available == 0;
+        self.diag.produce_trigger.store(trigger, Ordering::Relaxed);
+        stat!(self, producer.on_transfer(count, trigger));
+        // ring bytes in use, stamp headers included
+        stat!(
+            self,
+            producer.on_occupancy(new_tail.wrapping_sub(head) as usize)
+        );
         return trigger;
     }
 
     /// return: write user buffer to socket bytestream and determine whether to trigger async socket ops
     pub fn write(&mut self, buf: &[u8]) -> Result<(bool, usize)> {
+        if self.mode == RingMode::Overwrite {
+            return self.write_lossy(buf);
+        }
+        if self.mode == RingMode::Stamped {
+            return Ok(self.write_stamped(buf));
+        }
+
         let head = self.head.load(Ordering::Acquire);
+        self.note_head(head);
         let tail = self.tail.load(Ordering::Relaxed);
 
         let available = tail.wrapping_sub(head) as usize;
@@ -352,7 +751,7 @@ impl RingBuf {
         }
 
         let (first_len, has_second) = {
-            let to_end = self.len() - write_pos;
+            let to_end = self.to_end(write_pos);
             if to_end < write_size {
                 (to_end, true)
             } else {
@@ -360,15 +759,20 @@ impl RingBuf {
             }
         };
 
-        self.buf()[write_pos..write_pos + first_len].copy_from_slice(&buf[0..first_len]);
+        unsafe { self.write_raw(write_pos, &buf[0..first_len]) };
 
         if has_second {
             let second_len = write_size - first_len;
-            self.buf()[0..second_len].copy_from_slice(&buf[first_len..first_len + second_len]);
+            unsafe { self.write_raw(0, &buf[first_len..first_len + second_len]) };
         }
 
         self.tail
             .store(tail.wrapping_add(write_size as u32), Ordering::Release);
+        stat!(self, producer.on_transfer(write_size, empty));
+        stat!(self, producer.on_occupancy(available + write_size));
+        if write_size == 0 {
+            stat!(self, producer.on_zero_call());
+        }
         return Ok((empty, write_size));
     }
 
@@ -379,18 +783,635 @@ impl RingBuf {
         let available = tail.wrapping_sub(head) as usize;
         let space = self.len() - available;
 
-        if available < buf.len() {
-            let str = str::from_utf8(buf).unwrap();
-            print!("write full {}/{}/{}", space, buf.len(), str);
-            return Err(Error::QueueFull);
+        if space < buf.len() {
+            return Err(Error::InsufficientSpace {
+                requested: buf.len(),
+                free: space,
+            });
         }
 
         return self.write(buf);
     }
 
-    pub fn write_via_addr(&mut self, buf: u64, count: u64) -> (bool, usize) {
+    /// `write` from a caller buffer given by address.
+    ///
+    /// # Safety
+    /// `buf` must point to `count` initialized bytes that are valid for reads,
+    /// are not part of this ring, and are not written during the call.
+    pub unsafe fn write_via_addr(&mut self, buf: u64, count: u64) -> Result<(bool, usize)> {
         let ptr = buf as *const u8;
         let slice = unsafe { slice::from_raw_parts(ptr, count as usize) };
-        self.write(slice).expect("write_via_addr fail")
+        return self.write(slice);
+    }
+
+    /****************************************** overwrite *****************************************************/
+    // In `RingMode::Overwrite` `head` doubles as a sequence number: the producer
+    // advances it past bytes it is about to overwrite *before* touching them, and
+    // the consumer only keeps a copy if its CAS on `head` proves nobody moved it
+    // in the meantime. Comparing `head` against `read_seq`, where the consumer
+    // last left off, tells it exactly how far it was overrun.
+    //
+    // Like a seqlock, the consumer may copy bytes that are concurrently being
+    // overwritten; such a copy is always discarded.
+
+    /// return: (ring was empty before this write, how much was accepted)
+    /// Everything is accepted; if `buf` is longer than the ring only its last
+    /// `len()` bytes are stored and the rest is accounted as lost right away.
+    /// Only an `Overwrite` ring lets the producer move `head`.
+    pub fn write_lossy(&self, buf: &[u8]) -> Result<(bool, usize)> {
+        self.require_mode("write_lossy", RingMode::Overwrite)?;
+        let tail = self.tail.load(Ordering::Relaxed);
+        let new_tail = tail.wrapping_add(buf.len() as u32);
+        let keep = std::cmp::min(buf.len(), self.len());
+        let start = new_tail.wrapping_sub(keep as u32);
+        let min_head = new_tail.wrapping_sub(self.len() as u32);
+
+        let mut head = self.head.load(Ordering::Acquire);
+        let empty = head == tail;
+        while new_tail.wrapping_sub(head) as usize > self.len() {
+            match self.head.compare_exchange_weak(
+                head,
+                min_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(cur) => head = cur,
+            }
+        }
+        self.note_head(head);
+
+        let src = &buf[buf.len() - keep..];
+        let write_pos = (start & self.ring_mask) as usize;
+        let first_len = std::cmp::min(keep, self.to_end(write_pos));
+        unsafe { self.write_racy(write_pos, &src[0..first_len]) };
+        if first_len < keep {
+            unsafe { self.write_racy(0, &src[first_len..]) };
+        }
+
+        self.tail.store(new_tail, Ordering::Release);
+        stat!(self, producer.on_transfer(buf.len(), empty));
+        if buf.is_empty() {
+            stat!(self, producer.on_zero_call());
+        }
+        return Ok((empty, buf.len()));
+    }
+
+    //return (how much was lost to the producer overwriting it, how much read)
+    pub fn read_lossy(&self, buf: &mut [u8]) -> Result<(usize, usize)> {
+        self.require_mode("read_lossy", RingMode::Overwrite)?;
+        let mut head = self.head.load(Ordering::Acquire);
+        let mut lost = head.wrapping_sub(self.read_seq.load(Ordering::Relaxed)) as usize;
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            self.note_tail(tail);
+
+            if tail.wrapping_sub(head) as usize > self.len() {
+                // lapped since head was loaded: skip to the oldest byte still there
+                let oldest = tail.wrapping_sub(self.len() as u32);
+                let cur = match self.head.compare_exchange(
+                    head,
+                    oldest,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => oldest,
+                    Err(cur) => cur,
+                };
+                lost += cur.wrapping_sub(head) as usize;
+                head = cur;
+                continue;
+            }
+
+            let available = std::cmp::min(tail.wrapping_sub(head) as usize, buf.len());
+            if available == 0 {
+                self.read_seq.store(head, Ordering::Relaxed);
+                stat!(self, consumer.on_zero_call());
+                return Ok((lost, 0));
+            }
+
+            let read_pos = (head & self.ring_mask) as usize;
+            let first_len = std::cmp::min(available, self.to_end(read_pos));
+            unsafe { self.read_racy(read_pos, &mut buf[0..first_len]) };
+            if first_len < available {
+                unsafe { self.read_racy(0, &mut buf[first_len..available]) };
+            }
+
+            match self.head.compare_exchange(
+                head,
+                head.wrapping_add(available as u32),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.read_seq
+                        .store(head.wrapping_add(available as u32), Ordering::Relaxed);
+                    stat!(self, consumer.on_transfer(available, false));
+                    return Ok((lost, available));
+                }
+                Err(cur) => {
+                    lost += cur.wrapping_sub(head) as usize;
+                    head = cur;
+                }
+            }
+        }
+    }
+
+    /****************************************** stamped *******************************************************/
+    // In `RingMode::Stamped` each publication is a chunk: an 8 byte header with
+    // the payload length and the `tail` value the chunk is published under,
+    // then the payload, which never wraps. A consumer that loaded `tail` and
+    // finds a chunk at `head` whose stamp is not `head + 8 + len` is looking at
+    // bytes from an earlier lap: the stale-data outcome `memory_model` and
+    // verify.rkt look for, caught at run time instead of by the payload.
+
+    // write the header for a `count` byte payload at `tail`, return the new tail
+    fn stamp(&self, tail: u32, count: usize) -> u32 {
+        let new_tail = tail.wrapping_add((STAMP + count) as u32);
+        let mut header = [0u8; STAMP];
+        header[..4].copy_from_slice(&(count as u32).to_le_bytes());
+        header[4..].copy_from_slice(&new_tail.to_le_bytes());
+        self.copy_in(tail, &header);
+        return new_tail;
+    }
+
+    /// Check the chunk at `head` against a `tail` loaded with Acquire and
+    /// return its payload length.
+    pub fn check_stamp(&self, head: u32, tail: u32) -> Result<usize> {
+        let available = tail.wrapping_sub(head) as usize;
+        let mut header = [0u8; STAMP];
+        if available >= STAMP {
+            self.copy_out(head, &mut header);
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let stamp = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let end = head.wrapping_add((STAMP + len) as u32);
+        if available < STAMP || stamp != end || STAMP + len > available {
+            return Err(Error::StaleChunk {
+                head,
+                expected_tail: end,
+            });
+        }
+        return Ok(len);
+    }
+
+    /// `get_data_buf` for a stamped ring: the payload of the chunk at `head`,
+    /// to be released whole with `consume(len)`.
+    pub fn get_stamped_buf(&self) -> Result<(u64, usize)> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
+        if head == tail {
+            return Ok((0, 0));
+        }
+        let len = self.check_stamp(head, tail)?;
+        let read_pos = (head.wrapping_add(STAMP as u32) & self.ring_mask) as usize;
+        return Ok((self.buf_ptr() as u64 + read_pos as u64, len));
+    }
+
+    // one chunk per call; fails if it does not fit in buf
+    fn read_stamped(&self, buf: &mut [u8]) -> Result<(bool, usize)> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        self.note_tail(tail);
+        let available = tail.wrapping_sub(head) as usize;
+        if available == 0 {
+            stat!(self, consumer.on_zero_call());
+            return Ok((false, 0));
+        }
+        let len = self.check_stamp(head, tail)?;
+        if len > buf.len() {
+            return Err(Error::InsufficientSpace {
+                requested: len,
+                free: buf.len(),
+            });
+        }
+        self.copy_out(head.wrapping_add(STAMP as u32), &mut buf[..len]);
+        self.head
+            .store(head.wrapping_add((STAMP + len) as u32), Ordering::Release);
+        let full = available == self.len();
+        stat!(self, consumer.on_transfer(len, full));
+        return Ok((full, len));
+    }
+
+    // as much of buf as fits, one chunk per stretch of contiguous space
+    fn write_stamped(&self, buf: &[u8]) -> (bool, usize) {
+        let head = self.head.load(Ordering::Acquire);
+        self.note_head(head);
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        let empty = head == tail;
+
+        let mut written = 0;
+        while written < buf.len() {
+            let space = self.len() - tail.wrapping_sub(head) as usize;
+            if space <= STAMP {
+                break;
+            }
+            let payload = tail.wrapping_add(STAMP as u32);
+            let to_end = self.to_end((payload & self.ring_mask) as usize);
+            let n = (buf.len() - written).min(space - STAMP).min(to_end);
+            self.copy_in(payload, &buf[written..written + n]);
+            tail = self.stamp(tail, n);
+            written += n;
+        }
+
+        self.tail.store(tail, Ordering::Release);
+        stat!(self, producer.on_transfer(written, empty));
+        stat!(
+            self,
+            producer.on_occupancy(tail.wrapping_sub(head) as usize)
+        );
+        if written == 0 {
+            stat!(self, producer.on_zero_call());
+        }
+        return (empty, written);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn pattern(i: usize) -> u8 {
+        return (i % 251) as u8;
+    }
+
+    fn stream(from: usize, len: usize) -> Vec<u8> {
+        return (from..from + len).map(pattern).collect();
+    }
+
+    // one ring per backing store, sized to a single page so it can be mirrored
+    fn rings(mode: RingMode) -> Vec<RingBuf> {
+        let words = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize / 8;
+        // Miri cannot emulate memfd mappings
+        let backings: &[Backing] = if cfg!(miri) {
+            &[Backing::Heap]
+        } else {
+            &[Backing::Heap, Backing::Mirrored]
+        };
+        return backings
+            .iter()
+            .map(|b| RingBuf::from_storage(Storage::new(words, *b).unwrap(), mode))
+            .collect();
+    }
+
+    #[test]
+    fn blocking_fifo_across_wrap() {
+        for mut ring in rings(RingMode::Blocking) {
+            let mut out = vec![0u8; ring.len()];
+            let mut written = 0;
+            let mut read = 0;
+            for round in 0..50 {
+                let chunk = stream(written, 300 + round * 97 % 800);
+                let (_, n) = ring.write(&chunk).unwrap();
+                written += n;
+                assert!(ring.available_data_size() <= ring.len());
+
+                let (_, m) = ring.read(&mut out[..200 + round * 61 % 500]).unwrap();
+                assert_eq!(&out[..m], &stream(read, m)[..]);
+                read += m;
+                assert_eq!(ring.available_data_size(), written - read);
+            }
+        }
+    }
+
+    #[test]
+    fn blocking_write_stops_at_full() {
+        for mut ring in rings(RingMode::Blocking) {
+            let cap = ring.len();
+            let (empty, n) = ring.write(&stream(0, cap * 2)).unwrap();
+            assert!(empty);
+            assert_eq!(n, cap);
+            assert_eq!(ring.write(&[1, 2, 3]).unwrap(), (false, 0));
+            assert_eq!(ring.available_space(), 0);
+            assert!(ring.consume(4));
+            assert_eq!(ring.available_space(), 4);
+        }
+    }
+
+    #[test]
+    fn checked_ops_report_context() {
+        let mut ring = RingBuf::new(2);
+        ring.write(&stream(0, 10)).unwrap();
+
+        let err = ring.consume_with_check(11).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InsufficientData {
+                requested: 11,
+                available: 10
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "insufficient data: requested 11 bytes, 10 available"
+        );
+        assert!(matches!(
+            ring.produce_with_check(7),
+            Err(Error::InsufficientSpace {
+                requested: 7,
+                free: 6
+            })
+        ));
+        assert!(matches!(
+            ring.write_full(&stream(0, 7)),
+            Err(Error::InsufficientSpace {
+                requested: 7,
+                free: 6
+            })
+        ));
+        assert_eq!(ring.write_full(&stream(10, 6)).unwrap(), (false, 6));
+        assert!(matches!(
+            RingBuf::with_backing(3, Backing::Heap),
+            Err(Error::InvalidCapacity { len: 24 })
+        ));
+        #[cfg(not(miri))]
+        assert!(matches!(
+            RingBuf::with_backing(1, Backing::Mirrored),
+            Err(Error::InvalidCapacity { len: 8 })
+        ));
+    }
+
+    #[test]
+    fn peek_straddles_wrap() {
+        for mut ring in rings(RingMode::Blocking) {
+            let cap = ring.len();
+            let mut out = [0u8; 16];
+            ring.write(&stream(0, cap - 4)).unwrap();
+            assert_eq!(ring.skip(cap - 6).unwrap(), cap - 6);
+            ring.write(&stream(cap - 4, 10)).unwrap();
+
+            // 12 bytes from offset cap - 6, wrapping after the first 6
+            assert_eq!(ring.peek(&mut out[..8]).unwrap(), 8);
+            assert_eq!(&out[..8], &stream(cap - 6, 8)[..]);
+            assert_eq!(ring.peek_at(5, &mut out).unwrap(), 7);
+            assert_eq!(&out[..7], &stream(cap - 1, 7)[..]);
+            assert_eq!(ring.peek_at(12, &mut out).unwrap(), 0);
+            assert_eq!(ring.available_data_size(), 12);
+
+            assert_eq!(ring.skip(3).unwrap(), 3);
+            assert_eq!(ring.read(&mut out).unwrap(), (false, 9));
+            assert_eq!(&out[..9], &stream(cap - 3, 9)[..]);
+            assert_eq!(ring.skip(1).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn mirrored_views_are_contiguous() {
+        for mut ring in rings(RingMode::Blocking) {
+            let cap = ring.len();
+            ring.write(&stream(0, cap)).unwrap();
+            ring.skip(cap - 8).unwrap();
+            ring.write(&stream(cap, 16)).unwrap();
+
+            let mut iovs = [Iov { start: 0, len: 0 }; 2];
+            let mut data = SocketBufIovs {
+                iovs: &mut iovs,
+                cnt: 0,
+            };
+            ring.prepare_data_iovs(&mut data).unwrap();
+            let (addr, len) = ring.get_data_buf();
+
+            if ring.buf.is_mirrored() {
+                assert_eq!(data.cnt, 1);
+                assert_eq!(len, 24);
+                let view = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+                assert_eq!(view, &stream(cap - 8, 24)[..]);
+                assert_eq!(ring.get_write_buf().unwrap().unwrap().1, cap - 24);
+            } else {
+                assert_eq!(data.cnt, 2);
+                assert_eq!(len, 8);
+            }
+        }
+    }
+
+    #[test]
+    fn overwrite_drops_oldest() {
+        for mut ring in rings(RingMode::Overwrite) {
+            let cap = ring.len();
+            let mut out = vec![0u8; cap];
+
+            assert_eq!(ring.write(&stream(0, cap - 6)).unwrap(), (true, cap - 6));
+            assert_eq!(ring.write_lossy(&stream(cap - 6, 10)).unwrap(), (false, 10));
+            assert_eq!(ring.available_data_size(), cap);
+
+            assert_eq!(ring.read_lossy(&mut out).unwrap(), (4, cap));
+            assert_eq!(&out[..], &stream(4, cap)[..]);
+            assert_eq!(ring.read_lossy(&mut out).unwrap(), (0, 0));
+        }
+    }
+
+    #[test]
+    fn overwrite_larger_than_ring() {
+        for ring in rings(RingMode::Overwrite) {
+            let cap = ring.len();
+            let mut out = vec![0u8; cap];
+
+            ring.write_lossy(&stream(0, 3)).unwrap();
+            assert_eq!(
+                ring.write_lossy(&stream(3, cap * 3 + 2)).unwrap(),
+                (false, cap * 3 + 2)
+            );
+            assert_eq!(ring.read_lossy(&mut out[..5]).unwrap(), (cap * 2 + 5, 5));
+            assert_eq!(&out[..5], &stream(cap * 2 + 5, 5)[..]);
+            assert_eq!(ring.read_lossy(&mut out).unwrap(), (0, cap - 5));
+            assert_eq!(&out[..cap - 5], &stream(cap * 2 + 10, cap - 5)[..]);
+        }
+    }
+
+    #[test]
+    fn overwrite_read_catches_up_after_a_lap() {
+        for ring in rings(RingMode::Overwrite) {
+            let cap = ring.len();
+            let mut out = vec![0u8; cap * 2];
+
+            ring.write_lossy(&stream(0, cap * 2)).unwrap();
+            // a consumer that loaded head before the producer's last lap
+            ring.head.store(0, Ordering::Relaxed);
+            assert_eq!(ring.read_lossy(&mut out).unwrap(), (cap, cap));
+            assert_eq!(&out[..cap], &stream(cap, cap)[..]);
+            assert_eq!(ring.read_lossy(&mut out).unwrap(), (0, 0));
+        }
+    }
+
+    #[test]
+    fn overwrite_accounts_every_byte() {
+        for ring in rings(RingMode::Overwrite) {
+            let total = if cfg!(miri) { 1 << 12 } else { 1 << 22 };
+
+            let producer = ring.clone();
+            let writer = thread::spawn(move || {
+                let mut written = 0;
+                while written < total {
+                    let n = std::cmp::min(1 + written % 997, total - written);
+                    producer.write_lossy(&stream(written, n)).unwrap();
+                    written += n;
+                }
+            });
+
+            let mut out = [0u8; 300];
+            let mut pos = 0;
+            let mut lost_total = 0;
+            while pos < total {
+                let (lost, n) = ring.read_lossy(&mut out).unwrap();
+                pos += lost;
+                lost_total += lost;
+                assert_eq!(&out[..n], &stream(pos, n)[..]);
+                pos += n;
+            }
+            writer.join().unwrap();
+            assert_eq!(pos, total);
+            assert!(lost_total < total);
+        }
+    }
+
+    #[test]
+    fn stamped_chunks_round_trip() {
+        for mut ring in rings(RingMode::Stamped) {
+            let mut out = vec![0u8; ring.len()];
+            let mut written = 0;
+            let mut read = 0;
+            for round in 0..40 {
+                // copy in, zero-copy out
+                let (_, n) = ring
+                    .write(&stream(written, 100 + round * 37 % 900))
+                    .unwrap();
+                written += n;
+                loop {
+                    let (addr, len) = ring.get_data_buf();
+                    if len == 0 {
+                        break;
+                    }
+                    let got = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+                    assert_eq!(got, &stream(read, len)[..]);
+                    read += len;
+                    ring.consume(len);
+                }
+
+                // zero-copy in, copy out
+                let (addr, len) = ring.get_space_buf();
+                let n = len.min(50 + round * 53 % 700);
+                let chunk = stream(written, n);
+                unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), addr as *mut u8, n) };
+                ring.produce(n);
+                written += n;
+                let (_, m) = ring.read(&mut out).unwrap();
+                assert_eq!(&out[..m], &stream(read, m)[..]);
+                read += m;
+                assert_eq!(ring.available_data_size(), 0);
+            }
+            assert_eq!(read, written);
+        }
+    }
+
+    #[test]
+    fn stamped_ring_rejects_stale_chunk() {
+        let mut ring = RingBuf::with_mode(8, RingMode::Stamped);
+        ring.write(&[1u8; 24]).unwrap();
+        assert_eq!(
+            ring.read(&mut [0u8; 8]).unwrap_err().to_string(),
+            "insufficient space: requested 24 bytes, 8 free"
+        );
+        assert_eq!(ring.read(&mut [0u8; 64]).unwrap(), (false, 24));
+        ring.write(&[2u8; 24]).unwrap();
+        assert_eq!(ring.read(&mut [0u8; 64]).unwrap(), (false, 24));
+
+        // publish a third chunk without writing it, as if the tail store overtook
+        // the data (P2): slot 64 still holds the first chunk from the last lap
+        let head = ring.head.load(Ordering::Relaxed);
+        ring.tail.store(head + 32, Ordering::Release);
+        match ring.read(&mut [0u8; 64]) {
+            Err(Error::StaleChunk {
+                head,
+                expected_tail,
+            }) => assert_eq!((head, expected_tail), (64, 96)),
+            other => panic!("expected StaleChunk, got {:?}", other),
+        }
+        assert!(matches!(
+            ring.get_stamped_buf(),
+            Err(Error::StaleChunk { head: 64, .. })
+        ));
+        assert_eq!(ring.get_data_buf(), (0, 0));
+        assert_eq!(ring.stats().stale_chunks, 1);
+        assert_eq!(ring.head.load(Ordering::Relaxed), 64);
+    }
+
+    #[test]
+    fn stamped_ring_refuses_stamp_blind_calls_and_empty_chunks() {
+        let ring = RingBuf::with_mode(8, RingMode::Stamped);
+        let mut iovs = [Iov { start: 0, len: 0 }; 2];
+        let mut data = SocketBufIovs {
+            iovs: &mut iovs,
+            cnt: 0,
+        };
+        let unsupported = |res: Result<()>| {
+            return matches!(
+                res,
+                Err(Error::Unsupported {
+                    mode: RingMode::Stamped,
+                    ..
+                })
+            );
+        };
+        assert!(unsupported(ring.prepare_space_iovs(&mut data)));
+        assert!(unsupported(ring.prepare_data_iovs(&mut data)));
+        assert!(unsupported(ring.get_write_buf().map(|_| ())));
+        assert!(unsupported(ring.get_read_buf().map(|_| ())));
+        assert!(unsupported(ring.peek(&mut [0u8; 4]).map(|_| ())));
+        assert_eq!(
+            ring.skip(4).unwrap_err().to_string(),
+            "skip is not supported on a Stamped ring"
+        );
+
+        // an empty chunk would read as no data and stall the consumer
+        assert!(!ring.produce(0));
+        assert!(unsupported(ring.produce_with_check(0).map(|_| ())));
+        assert_eq!(ring.available_data_size(), 0);
+    }
+
+    #[test]
+    fn overwrite_ring_refuses_zero_copy_and_others_refuse_lossy_calls() {
+        let mut ring = RingBuf::with_mode(2, RingMode::Overwrite);
+        ring.write(&stream(0, 10)).unwrap();
+        let mut iovs = [Iov { start: 0, len: 0 }; 2];
+        let mut data = SocketBufIovs {
+            iovs: &mut iovs,
+            cnt: 0,
+        };
+        let unsupported = |res: Result<()>| {
+            return matches!(
+                res,
+                Err(Error::Unsupported {
+                    mode: RingMode::Overwrite,
+                    ..
+                })
+            );
+        };
+        assert!(unsupported(ring.prepare_data_iovs(&mut data)));
+        assert!(unsupported(ring.prepare_space_iovs(&mut data)));
+        assert!(unsupported(ring.get_read_buf().map(|_| ())));
+        assert!(unsupported(ring.get_write_buf().map(|_| ())));
+        assert!(unsupported(ring.consume_with_check(4).map(|_| ())));
+        assert!(unsupported(ring.produce_with_check(4).map(|_| ())));
+        assert_eq!(
+            (ring.get_data_buf(), ring.get_space_buf()),
+            ((0, 0), (0, 0))
+        );
+        assert!(!ring.consume(4) && !ring.produce(4));
+        assert_eq!(ring.stats().head, 0);
+        assert_eq!(ring.stats().tail, 10);
+        let (producer, consumer) = ring.split();
+        assert!(producer.get_space_buf().is_err() && producer.commit(4).is_err());
+        assert!(consumer.get_data_buf().is_err() && consumer.commit(4).is_err());
+
+        // only an overwrite ring lets the producer move head
+        let blocking = RingBuf::new(2);
+        assert_eq!(
+            blocking.write_lossy(&[1]).unwrap_err().to_string(),
+            "write_lossy is not supported on a Blocking ring"
+        );
+        assert!(blocking.read_lossy(&mut [0u8; 4]).is_err());
+        assert_eq!(blocking.available_data_size(), 0);
     }
 }
@@ -1,26 +1,14 @@
-#![allow(dead_code)]
+#![allow(dead_code, clippy::needless_return)]
+use crate::error::{Error, Result};
 use std::{
     cell::UnsafeCell,
-    fmt, slice, str,
+    fmt, ptr, slice,
     sync::{
         Arc,
         atomic::{AtomicU32, Ordering},
     },
 };
 
-#[derive(Debug)]
-pub enum SysErr {
-    EINVAL,
-}
-
-#[derive(Debug)]
-pub enum Error {
-    SysError(SysErr),
-    QueueFull,
-}
-
-pub type Result<T> = std::result::Result<T, Error>;
-
 #[derive(Debug, Clone, Copy)]
 pub struct Iov {
     pub start: u64,
@@ -55,9 +43,11 @@ impl fmt::Debug for RingBufSeq {
 
 impl RingBufSeq {
     pub fn is_power_of_two(x: usize) -> bool {
-        return (x & (x - 1)) == 0;
+        return x != 0 && (x & (x - 1)) == 0;
     }
 
+    // the ring itself is Send and Sync through the unsafe impls above
+    #[allow(clippy::arc_with_non_send_sync)]
     pub fn new(count: usize) -> Self {
         return Self {
             buf: Arc::new((0..count).map(|_| UnsafeCell::new(0)).collect()),
@@ -77,11 +67,28 @@ impl RingBufSeq {
         return (self.ring_mask + 1) as usize;
     }
 
+    /// Base address of the ring storage. Bytes behind it may be written through
+    /// this pointer only by the side that currently owns them under the
+    /// head/tail protocol; never turn it into a `&mut [u8]` over the whole ring.
+    #[inline]
+    pub fn buf_ptr(&self) -> *mut u8 {
+        return UnsafeCell::raw_get(self.buf.as_ptr()) as *mut u8;
+    }
+
+    // copy ring bytes [pos, pos + dst.len()) out into dst
+    // Safety: the range must lie inside the storage and be readable by the caller's side.
+    #[inline]
+    unsafe fn read_raw(&self, pos: usize, dst: &mut [u8]) {
+        debug_assert!(pos + dst.len() <= self.buf.len() * 8);
+        unsafe { ptr::copy_nonoverlapping(self.buf_ptr().add(pos), dst.as_mut_ptr(), dst.len()) };
+    }
+
+    // copy src into ring bytes [pos, pos + src.len())
+    // Safety: the range must lie inside the storage and be owned by the producer side.
     #[inline]
-    pub fn buf(&self) -> &mut [u8] {
-        let ptr = self.buf.as_ptr() as *mut u8;
-        let len = self.buf.len() * 8;
-        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    unsafe fn write_raw(&self, pos: usize, src: &[u8]) {
+        debug_assert!(pos + src.len() <= self.buf.len() * 8);
+        unsafe { ptr::copy_nonoverlapping(src.as_ptr(), self.buf_ptr().add(pos), src.len()) };
     }
 
     pub fn available_data_size(&self) -> usize {
@@ -117,11 +124,11 @@ impl RingBufSeq {
             }
         };
 
-        buf[0..first_len].copy_from_slice(&self.buf()[read_pos..read_pos + first_len]);
+        unsafe { self.read_raw(read_pos, &mut buf[0..first_len]) };
 
         if has_second {
             let second_len = available - first_len;
-            buf[first_len..first_len + second_len].copy_from_slice(&self.buf()[0..second_len])
+            unsafe { self.read_raw(0, &mut buf[first_len..first_len + second_len]) };
         }
 
         self.head
@@ -129,7 +136,12 @@ impl RingBufSeq {
         return Ok((full, available));
     }
 
-    pub fn read_via_addr(&self, buf: u64, count: u64) -> (bool, usize) {
+    /// `read` into a caller buffer given by address.
+    ///
+    /// # Safety
+    /// `buf` must point to `count` bytes that are valid for writes, are not part
+    /// of this ring, and are not accessed by anything else during the call.
+    pub unsafe fn read_via_addr(&self, buf: u64, count: u64) -> (bool, usize) {
         let ptr = buf as *mut u8;
         let slice = unsafe { slice::from_raw_parts_mut(ptr, count as usize) };
         let res = self.read(slice).expect("read_via_addr get error");
@@ -150,9 +162,9 @@ impl RingBufSeq {
         let read_pos = (head & self.ring_mask) as usize;
         let to_end = self.len() - read_pos;
         if to_end < available {
-            return Some((self.buf.as_ptr() as u64 + read_pos as u64, to_end, true));
+            return Some((self.buf_ptr() as u64 + read_pos as u64, to_end, true));
         } else {
-            return Some((self.buf.as_ptr() as u64 + read_pos as u64, available, false));
+            return Some((self.buf_ptr() as u64 + read_pos as u64, available, false));
         }
     }
 
@@ -170,9 +182,9 @@ impl RingBufSeq {
         let read_pos = (head & self.ring_mask) as usize;
         let to_end = self.len() - read_pos;
         if to_end < available {
-            return (self.buf.as_ptr() as u64 + read_pos as u64, to_end);
+            return (self.buf_ptr() as u64 + read_pos as u64, to_end);
         } else {
-            return (self.buf.as_ptr() as u64 + read_pos as u64, available);
+            return (self.buf_ptr() as u64 + read_pos as u64, available);
         }
     }
 
@@ -193,16 +205,16 @@ impl RingBufSeq {
         let read_pos = (head & self.ring_mask) as usize;
         let to_end = self.len() - read_pos;
         if to_end < available {
-            iovs[0].start = &self.buf()[read_pos as usize] as *const _ as u64;
-            iovs[0].len = to_end as usize;
+            iovs[0].start = self.buf_ptr() as u64 + read_pos as u64;
+            iovs[0].len = to_end;
 
-            iovs[1].start = &self.buf()[0] as *const _ as u64;
+            iovs[1].start = self.buf_ptr() as u64;
             iovs[1].len = available - to_end;
 
             data.cnt = 2;
         } else {
-            iovs[0].start = &self.buf()[read_pos as usize] as *const _ as u64;
-            iovs[0].len = available as usize;
+            iovs[0].start = self.buf_ptr() as u64 + read_pos as u64;
+            iovs[0].len = available;
 
             data.cnt = 1;
         }
@@ -211,7 +223,10 @@ impl RingBufSeq {
     pub fn consume_with_check(&self, count: usize) -> Result<bool> {
         let available = self.available_data_size();
         if available < count {
-            return Err(Error::SysError(SysErr::EINVAL));
+            return Err(Error::InsufficientData {
+                requested: count,
+                available,
+            });
         }
 
         let trigger = self.consume(count);
@@ -247,13 +262,9 @@ impl RingBufSeq {
 
         let to_end = self.len() - write_pos;
         if to_end < write_size {
-            return Some((self.buf.as_ptr() as u64 + write_pos as u64, to_end, true));
+            return Some((self.buf_ptr() as u64 + write_pos as u64, to_end, true));
         } else {
-            return Some((
-                self.buf.as_ptr() as u64 + write_pos as u64,
-                write_size,
-                false,
-            ));
+            return Some((self.buf_ptr() as u64 + write_pos as u64, write_size, false));
         }
     }
 
@@ -278,16 +289,16 @@ impl RingBufSeq {
 
         //error!("GetSpaceIovs available is {}, toEnd is {}", self.available, toEnd);
         if to_end < write_size {
-            iovs[0].start = &self.buf()[write_pos as usize] as *const _ as u64;
-            iovs[0].len = to_end as usize;
+            iovs[0].start = self.buf_ptr() as u64 + write_pos as u64;
+            iovs[0].len = to_end;
 
-            iovs[1].start = &self.buf()[0] as *const _ as u64;
+            iovs[1].start = self.buf_ptr() as u64;
             iovs[1].len = write_size - to_end;
 
             data.cnt = 2;
         } else {
-            iovs[0].start = &self.buf()[write_pos as usize] as *const _ as u64;
-            iovs[0].len = write_size as usize;
+            iovs[0].start = self.buf_ptr() as u64 + write_pos as u64;
+            iovs[0].len = write_size;
 
             data.cnt = 1;
         }
@@ -307,16 +318,19 @@ impl RingBufSeq {
 
         let to_end = self.len() - write_pos;
         if to_end < write_size {
-            return (self.buf.as_ptr() as u64 + write_pos as u64, to_end);
+            return (self.buf_ptr() as u64 + write_pos as u64, to_end);
         } else {
-            return (self.buf.as_ptr() as u64 + write_pos as u64, write_size);
+            return (self.buf_ptr() as u64 + write_pos as u64, write_size);
         }
     }
 
     pub fn produce_with_check(&self, count: usize) -> Result<bool> {
         let available = self.available_data_size();
         if available + count > self.len() {
-            return Err(Error::SysError(SysErr::EINVAL));
+            return Err(Error::InsufficientSpace {
+                requested: count,
+                free: self.len() - available,
+            });
         }
 
         let trigger = self.produce(count);
@@ -360,11 +374,11 @@ impl RingBufSeq {
             }
         };
 
-        self.buf()[write_pos..write_pos + first_len].copy_from_slice(&buf[0..first_len]);
+        unsafe { self.write_raw(write_pos, &buf[0..first_len]) };
 
         if has_second {
             let second_len = write_size - first_len;
-            self.buf()[0..second_len].copy_from_slice(&buf[first_len..first_len + second_len]);
+            unsafe { self.write_raw(0, &buf[first_len..first_len + second_len]) };
         }
 
         self.tail
@@ -379,16 +393,22 @@ impl RingBufSeq {
         let available = tail.wrapping_sub(head) as usize;
         let space = self.len() - available;
 
-        if available < buf.len() {
-            let str = str::from_utf8(buf).unwrap();
-            print!("write full {}/{}/{}", space, buf.len(), str);
-            return Err(Error::QueueFull);
+        if space < buf.len() {
+            return Err(Error::InsufficientSpace {
+                requested: buf.len(),
+                free: space,
+            });
         }
 
         return self.write(buf);
     }
 
-    pub fn write_via_addr(&mut self, buf: u64, count: u64) -> (bool, usize) {
+    /// `write` from a caller buffer given by address.
+    ///
+    /// # Safety
+    /// `buf` must point to `count` initialized bytes that are valid for reads,
+    /// are not part of this ring, and are not written during the call.
+    pub unsafe fn write_via_addr(&mut self, buf: u64, count: u64) -> (bool, usize) {
         let ptr = buf as *const u8;
         let slice = unsafe { slice::from_raw_parts(ptr, count as usize) };
         self.write(slice).expect("write_via_addr fail")
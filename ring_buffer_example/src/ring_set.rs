@@ -0,0 +1,354 @@
+#![allow(dead_code, clippy::needless_return)]
+use crate::notify::Notify;
+use crate::ring_buffer::{Error, Result, RingBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering, fence};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+struct Shared {
+    /// One bit per registered ring, set by its producer after publishing.
+    ready: Vec<AtomicU64>,
+    waiting: AtomicBool,
+    lock: Mutex<()>,
+    cond: Condvar,
+    notifier: OnceLock<Arc<dyn Notify>>,
+}
+
+impl Shared {
+    fn word_bit(index: usize) -> (usize, u64) {
+        return (index / 64, 1u64 << (index % 64));
+    }
+
+    fn mark(&self, index: usize) {
+        let (word, bit) = Self::word_bit(index);
+        if self.ready[word].fetch_or(bit, Ordering::SeqCst) & bit != 0 {
+            return;
+        }
+        if let Some(notifier) = self.notifier.get() {
+            notifier.notify();
+        }
+        if self.waiting.load(Ordering::SeqCst) {
+            let _guard = self.lock.lock().unwrap();
+            self.cond.notify_all();
+        }
+    }
+
+    fn any_ready(&self) -> bool {
+        return self.ready.iter().any(|w| w.load(Ordering::SeqCst) != 0);
+    }
+}
+
+/// Producer-side link to a ring's bit in a `RingSet`.
+#[derive(Clone)]
+pub struct ReadySignal {
+    shared: Arc<Shared>,
+    index: usize,
+}
+
+impl ReadySignal {
+    pub fn index(&self) -> usize {
+        return self.index;
+    }
+
+    /// `ring.produce(count)`, then mark the ring ready.
+    pub fn produce(&self, ring: &RingBuf, count: usize) -> bool {
+        let trigger = ring.produce(count);
+        self.publish(trigger);
+        return trigger;
+    }
+
+    /// `ring.write(buf)`, then mark the ring ready if anything was written.
+    pub fn write(&self, ring: &mut RingBuf, buf: &[u8]) -> Result<(bool, usize)> {
+        let res = ring.write(buf)?;
+        if res.1 > 0 {
+            self.publish(res.0);
+        }
+        return Ok(res);
+    }
+
+    /// Call after publishing with the `produce` trigger. An empty trigger sets
+    /// the bit straight away; otherwise the bit is only set if the consumer has
+    /// cleared it since, which the fence pairs with the one in `RingSet::poll`.
+    pub fn publish(&self, trigger: bool) {
+        if !trigger {
+            fence(Ordering::SeqCst);
+            let (word, bit) = Shared::word_bit(self.index);
+            if self.shared.ready[word].load(Ordering::Relaxed) & bit != 0 {
+                return;
+            }
+        }
+        self.shared.mark(self.index);
+    }
+}
+
+/// Many rings served by one consumer. Producers mark their ring ready through
+/// a `ReadySignal`; `poll` and `select` hand out ready rings round robin, so a
+/// busy ring cannot starve the others.
+pub struct RingSet {
+    shared: Arc<Shared>,
+    rings: Vec<RingBuf>,
+    /// Where the next scan starts.
+    cursor: usize,
+    /// Ring handed out by the last poll; re-marked if it was not drained.
+    last: Option<usize>,
+}
+
+impl RingSet {
+    /// A set with room for `capacity` rings.
+    pub fn new(capacity: usize) -> Self {
+        return Self {
+            shared: Arc::new(Shared {
+                ready: (0..capacity.div_ceil(64))
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
+                waiting: AtomicBool::new(false),
+                lock: Mutex::new(()),
+                cond: Condvar::new(),
+                notifier: OnceLock::new(),
+            }),
+            rings: Vec::new(),
+            cursor: 0,
+            last: None,
+        };
+    }
+
+    pub fn capacity(&self) -> usize {
+        return self.shared.ready.len() * 64;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.rings.len();
+    }
+
+    pub fn ring(&self, index: usize) -> &RingBuf {
+        return &self.rings[index];
+    }
+
+    /// Signal `notifier` whenever a ring becomes ready, for waiting on the set
+    /// from an event loop. Can be set once.
+    pub fn set_notifier(&self, notifier: Arc<dyn Notify>) -> bool {
+        return self.shared.notifier.set(notifier).is_ok();
+    }
+
+    /// Add `ring` to the set. Data already in it is reported by the next poll.
+    pub fn register(&mut self, ring: RingBuf) -> Result<ReadySignal> {
+        let index = self.rings.len();
+        if index >= self.capacity() {
+            return Err(Error::SetFull {
+                capacity: self.capacity(),
+            });
+        }
+        self.rings.push(ring);
+        let signal = ReadySignal {
+            shared: self.shared.clone(),
+            index,
+        };
+        if self.rings[index].available_data_size() > 0 {
+            self.shared.mark(index);
+        }
+        return Ok(signal);
+    }
+
+    /// Next ready ring after the previous one, without blocking. Its bit is
+    /// cleared before it is returned; if the caller leaves data behind, the next
+    /// call marks it again, behind the rings already waiting.
+    pub fn poll(&mut self) -> Option<usize> {
+        if let Some(last) = self.last.take()
+            && self.rings[last].available_data_size() > 0
+        {
+            self.shared.mark(last);
+        }
+
+        let words = self.shared.ready.len();
+        if words == 0 {
+            return None;
+        }
+        let start = self.cursor;
+        for step in 0..=words {
+            let word = (start / 64 + step) % words;
+            let mut bits = self.shared.ready[word].load(Ordering::Relaxed);
+            // first pass over the starting word only looks at bits from the cursor on
+            if step == 0 {
+                bits &= !0u64 << (start % 64);
+            }
+            while bits != 0 {
+                let index = word * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let (w, bit) = Shared::word_bit(index);
+                if self.shared.ready[w].fetch_and(!bit, Ordering::SeqCst) & bit == 0 {
+                    continue;
+                }
+                // pairs with the fence in `ReadySignal::publish`: either we see the
+                // data, or the producer sees the bit clear and sets it again
+                fence(Ordering::SeqCst);
+                if self.rings[index].available_data_size() > 0 {
+                    self.cursor = (index + 1) % self.capacity();
+                    self.last = Some(index);
+                    return Some(index);
+                }
+            }
+        }
+        return None;
+    }
+
+    /// Like `poll`, but waits up to `timeout` (forever if `None`) for a ring to
+    /// become ready.
+    pub fn select(&mut self, timeout: Option<Duration>) -> Option<usize> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(index) = self.poll() {
+                return Some(index);
+            }
+
+            let mut guard = self.shared.lock.lock().unwrap();
+            self.shared.waiting.store(true, Ordering::SeqCst);
+            while !self.shared.any_ready() {
+                match deadline {
+                    None => guard = self.shared.cond.wait(guard).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            self.shared.waiting.store(false, Ordering::SeqCst);
+                            return None;
+                        }
+                        guard = self
+                            .shared
+                            .cond
+                            .wait_timeout(guard, deadline - now)
+                            .unwrap()
+                            .0;
+                    }
+                }
+            }
+            self.shared.waiting.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn set_of(n: usize) -> (RingSet, Vec<(RingBuf, ReadySignal)>) {
+        let mut set = RingSet::new(n);
+        let producers = (0..n)
+            .map(|_| {
+                let ring = RingBuf::new(2);
+                let signal = set.register(ring.clone()).unwrap();
+                (ring, signal)
+            })
+            .collect();
+        return (set, producers);
+    }
+
+    #[test]
+    fn poll_is_round_robin_and_rearms_leftovers() {
+        let (mut set, mut producers) = set_of(70);
+        assert_eq!(set.poll(), None);
+
+        for i in [3, 65, 40] {
+            let (ring, signal) = &mut producers[i];
+            signal.write(ring, &[1, 2]).unwrap();
+        }
+        // nothing is drained, so each ring comes back after the others
+        let order: Vec<_> = (0..6).map(|_| set.poll().unwrap()).collect();
+        assert_eq!(order, [3, 40, 65, 3, 40, 65]);
+
+        set.ring(3).skip(2).unwrap();
+        set.ring(40).skip(2).unwrap();
+        assert_eq!(set.poll(), Some(65));
+        set.ring(65).skip(2).unwrap();
+        assert_eq!(set.poll(), None);
+        // the bitmap rounds the 70 rings up to 128 slots
+        while set.len() < 128 {
+            assert!(set.register(RingBuf::new(2)).is_ok());
+        }
+        assert!(matches!(
+            set.register(RingBuf::new(2)),
+            Err(Error::SetFull { capacity: 128 })
+        ));
+    }
+
+    #[test]
+    fn select_wakes_on_produce_and_times_out() {
+        let (mut set, mut producers) = set_of(4);
+        assert_eq!(set.select(Some(Duration::from_millis(5))), None);
+
+        let (ring, signal) = producers.remove(2);
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            let (addr, _) = ring.get_space_buf();
+            unsafe { *(addr as *mut u8) = 9 };
+            signal.produce(&ring, 1);
+        });
+        assert_eq!(set.select(None), Some(2));
+        writer.join().unwrap();
+    }
+
+    struct Count(AtomicU64);
+
+    impl Notify for Count {
+        fn notify(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn notifier_is_set_once_and_fires_per_ready_edge() {
+        let (mut set, mut producers) = set_of(2);
+        let count = Arc::new(Count(AtomicU64::new(0)));
+        assert!(set.set_notifier(count.clone()));
+        assert!(!set.set_notifier(Arc::new(Count(AtomicU64::new(0)))));
+
+        let (ring, signal) = &mut producers[1];
+        signal.write(ring, &[1]).unwrap();
+        // the bit is already set, so the second publish does not signal again
+        signal.write(ring, &[2]).unwrap();
+        assert_eq!(count.0.load(Ordering::Relaxed), 1);
+
+        assert_eq!(set.poll(), Some(1));
+        set.ring(1).skip(2).unwrap();
+        signal.write(ring, &[3]).unwrap();
+        assert_eq!(count.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn fan_in_from_many_producers() {
+        let rings = 8;
+        let per_ring = 2000;
+        let (mut set, producers) = set_of(rings);
+
+        let writers: Vec<_> = producers
+            .into_iter()
+            .enumerate()
+            .map(|(id, (mut ring, signal))| {
+                thread::spawn(move || {
+                    let mut sent = 0;
+                    while sent < per_ring {
+                        let chunk = [id as u8; 7];
+                        let n = chunk.len().min(per_ring - sent);
+                        let (_, n) = signal.write(&mut ring, &chunk[..n]).unwrap();
+                        if n == 0 {
+                            thread::yield_now();
+                        }
+                        sent += n;
+                    }
+                })
+            })
+            .collect();
+
+        let mut received = vec![0; rings];
+        let mut buf = [0u8; 16];
+        while received.iter().sum::<usize>() < rings * per_ring {
+            let index = set.select(Some(Duration::from_secs(10))).unwrap();
+            let (_, n) = set.ring(index).read(&mut buf).unwrap();
+            assert!(buf[..n].iter().all(|&b| b as usize == index));
+            received[index] += n;
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(received, vec![per_ring; rings]);
+    }
+}
@@ -0,0 +1,282 @@
+//! Fixed-size, aligned slots on top of a `RingBuf`, for posting buffers to a
+//! NIC or to io_uring.
+//!
+//! The slot size is a power of two that divides the ring, and `head` and
+//! `tail` only ever move by whole slots, so a slot never straddles the wrap and
+//! every slot starts at a multiple of the alignment. The producer acquires free
+//! slots and publishes them in order; the consumer gets the published ones and
+//! releases them in the same order. Each slot comes as its own `Iov`; since
+//! they all lie inside the ring storage, they are also valid offsets into the
+//! fixed buffer `UringIo` registers.
+#![allow(dead_code, clippy::needless_return)]
+use crate::ring_buffer::{Error, Iov, Result, RingBuf, RingMode, SocketBufIovs};
+
+pub struct SlotRing {
+    ring: RingBuf,
+    slot_size: usize,
+}
+
+impl SlotRing {
+    /// Carve `ring` into slots of `slot_size` bytes, each aligned to `align`.
+    /// Fails with `InvalidSlots` if the sizes do not fit together, if the
+    /// storage itself is less aligned (heap storage is only 8 byte aligned; use
+    /// `Backing::Mapped` for page alignment), or if the ring is not empty, and
+    /// with `Unsupported` if it is not a `Blocking` ring.
+    pub fn new(ring: RingBuf, slot_size: usize, align: usize) -> Result<Self> {
+        let invalid = |reason| {
+            return Err(Error::InvalidSlots {
+                slot_size,
+                align,
+                reason,
+            });
+        };
+        if ring.mode != RingMode::Blocking {
+            return Err(Error::Unsupported {
+                op: "SlotRing",
+                mode: ring.mode,
+            });
+        }
+        if !RingBuf::is_power_of_two(slot_size)
+            || !RingBuf::is_power_of_two(align)
+            || align > slot_size
+            || slot_size > ring.len()
+        {
+            return invalid("sizes must be powers of two with align <= slot size <= ring length");
+        }
+        if !(ring.buf_ptr() as usize).is_multiple_of(align) {
+            return invalid("ring storage is less aligned than the slots");
+        }
+        if ring.available_data_size() != 0 {
+            return invalid("ring is not empty");
+        }
+        if !(ring.stats().head as usize).is_multiple_of(slot_size) {
+            return invalid("head is not on a slot boundary");
+        }
+        return Ok(Self { ring, slot_size });
+    }
+
+    pub fn ring(&self) -> &RingBuf {
+        return &self.ring;
+    }
+
+    pub fn slot_size(&self) -> usize {
+        return self.slot_size;
+    }
+
+    pub fn slots(&self) -> usize {
+        return self.ring.len() / self.slot_size;
+    }
+
+    pub fn ready_slots(&self) -> usize {
+        return self.ring.available_data_size() / self.slot_size;
+    }
+
+    pub fn free_slots(&self) -> usize {
+        return self.ring.available_space() / self.slot_size;
+    }
+
+    // one Iov per slot, starting at ring position pos
+    fn fill(&self, pos: u32, slots: usize, data: &mut SocketBufIovs) {
+        let count = slots.min(data.iovs.len());
+        for (i, iov) in data.iovs[..count].iter_mut().enumerate() {
+            let offset = (pos as usize + i * self.slot_size) & self.ring.ring_mask as usize;
+            *iov = Iov {
+                start: self.ring.buf_ptr() as u64 + offset as u64,
+                len: self.slot_size,
+            };
+        }
+        data.cnt = count;
+    }
+
+    /// Producer: describe the free slots, in order, up to `data.iovs.len()`.
+    /// They stay the producer's until `publish`.
+    pub fn acquire(&self, data: &mut SocketBufIovs) {
+        let (addr, _) = self.ring.get_space_buf();
+        let free = self.free_slots();
+        if free == 0 || addr == 0 {
+            data.cnt = 0;
+            return;
+        }
+        let pos = (addr - self.ring.buf_ptr() as u64) as u32;
+        self.fill(pos, free, data);
+    }
+
+    /// Producer: hand the first `count` acquired slots to the consumer.
+    /// Returns the ring's empty trigger.
+    pub fn publish(&self, count: usize) -> bool {
+        return self.ring.produce(count * self.slot_size);
+    }
+
+    /// Consumer: describe the published slots, in order, up to `data.iovs.len()`.
+    pub fn ready(&self, data: &mut SocketBufIovs) {
+        let (addr, _) = self.ring.get_data_buf();
+        let ready = self.ready_slots();
+        if ready == 0 || addr == 0 {
+            data.cnt = 0;
+            return;
+        }
+        let pos = (addr - self.ring.buf_ptr() as u64) as u32;
+        self.fill(pos, ready, data);
+    }
+
+    /// Consumer: give the first `count` ready slots back to the producer.
+    /// Returns the ring's full trigger.
+    pub fn release(&self, count: usize) -> bool {
+        return self.ring.consume(count * self.slot_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::RingBuilder;
+    use std::thread;
+
+    fn iovs_of(slots: &SlotRing, acquire: bool, max: usize) -> Vec<Iov> {
+        let mut iovs = vec![Iov { start: 0, len: 0 }; max];
+        let mut data = SocketBufIovs {
+            iovs: &mut iovs,
+            cnt: 0,
+        };
+        if acquire {
+            slots.acquire(&mut data);
+        } else {
+            slots.ready(&mut data);
+        }
+        let cnt = data.cnt;
+        iovs.truncate(cnt);
+        return iovs;
+    }
+
+    fn fill(iov: &Iov, byte: u8) {
+        unsafe { std::ptr::write_bytes(iov.start as *mut u8, byte, iov.len) };
+    }
+
+    fn first_byte(iov: &Iov) -> u8 {
+        return unsafe { *(iov.start as *const u8) };
+    }
+
+    #[test]
+    fn slots_stay_whole_and_aligned_across_the_wrap() {
+        // 4 KiB ring of eight 512 byte slots
+        let ring = RingBuf::new(512);
+        let base = ring.buf_ptr() as u64;
+        let slots = SlotRing::new(ring, 512, 8).unwrap();
+        assert_eq!(slots.slots(), 8);
+
+        let free = iovs_of(&slots, true, 16);
+        assert_eq!(free.len(), 8);
+        for (i, iov) in free.iter().enumerate() {
+            assert_eq!((iov.start - base, iov.len), (i as u64 * 512, 512));
+            fill(iov, i as u8);
+        }
+        assert!(slots.publish(6));
+        assert_eq!(iovs_of(&slots, true, 16).len(), 2);
+
+        let ready = iovs_of(&slots, false, 4);
+        assert_eq!(
+            ready.iter().map(first_byte).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        slots.release(5);
+
+        // free slots now run 6, 7, then wrap to 0..5
+        let free = iovs_of(&slots, true, 16);
+        let offsets: Vec<_> = free.iter().map(|iov| (iov.start - base) / 512).collect();
+        assert_eq!(offsets, [6, 7, 0, 1, 2, 3, 4]);
+        for (i, iov) in free.iter().enumerate() {
+            fill(iov, 10 + i as u8);
+        }
+        slots.publish(4);
+        let ready = iovs_of(&slots, false, 16);
+        assert_eq!(
+            ready.iter().map(first_byte).collect::<Vec<_>>(),
+            [5, 10, 11, 12, 13]
+        );
+    }
+
+    #[test]
+    fn bad_slot_layouts_are_rejected() {
+        let reason = |ring: &RingBuf, slot_size, align| {
+            return match SlotRing::new(ring.clone(), slot_size, align) {
+                Err(Error::InvalidSlots { reason, .. }) => reason,
+                other => panic!("expected InvalidSlots, got {:?}", other.err()),
+            };
+        };
+        let ring = RingBuf::new(512);
+        let sizes = "sizes must be powers of two with align <= slot size <= ring length";
+        assert_eq!(reason(&ring, 384, 8), sizes);
+        assert_eq!(reason(&ring, 8192, 8), sizes);
+        assert_eq!(reason(&ring, 512, 1024), sizes);
+        // heap storage is only guaranteed 8 byte alignment
+        if !(ring.buf_ptr() as usize).is_multiple_of(4096) {
+            assert_eq!(
+                reason(&ring, 4096, 4096),
+                "ring storage is less aligned than the slots"
+            );
+        }
+
+        let mut used = RingBuf::new(512);
+        used.write(&[1]).unwrap();
+        assert_eq!(reason(&used, 512, 8), "ring is not empty");
+        used.skip(1).unwrap();
+        assert_eq!(reason(&used, 512, 8), "head is not on a slot boundary");
+
+        let stamped = RingBuf::with_mode(512, RingMode::Stamped);
+        assert!(matches!(
+            SlotRing::new(stamped, 512, 8),
+            Err(Error::Unsupported { op: "SlotRing", .. })
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "mmap placement is not modelled")]
+    fn page_aligned_slots_from_mapped_storage() {
+        let ring = RingBuilder::new(4096).prefault(true).build().unwrap();
+        let slots = SlotRing::new(ring, 4096, 4096).unwrap();
+        assert_eq!(slots.slots(), 8);
+        for iov in iovs_of(&slots, true, 8) {
+            assert_eq!(iov.start % 4096, 0);
+        }
+    }
+
+    #[test]
+    fn slots_stream_between_threads() {
+        let slots = std::sync::Arc::new(SlotRing::new(RingBuf::new(128), 64, 8).unwrap());
+        let total = 5000usize;
+        let producer = {
+            let slots = slots.clone();
+            thread::spawn(move || {
+                let mut sent = 0;
+                while sent < total {
+                    let free = iovs_of(&slots, true, 3);
+                    let n = free.len().min(total - sent);
+                    for (i, iov) in free[..n].iter().enumerate() {
+                        fill(iov, (sent + i) as u8);
+                    }
+                    if n == 0 {
+                        thread::yield_now();
+                        continue;
+                    }
+                    slots.publish(n);
+                    sent += n;
+                }
+            })
+        };
+        let mut received = 0;
+        while received < total {
+            let ready = iovs_of(&slots, false, 5);
+            if ready.is_empty() {
+                thread::yield_now();
+                continue;
+            }
+            for (i, iov) in ready.iter().enumerate() {
+                let bytes = unsafe { std::slice::from_raw_parts(iov.start as *const u8, iov.len) };
+                assert!(bytes.iter().all(|&b| b == (received + i) as u8));
+            }
+            slots.release(ready.len());
+            received += ready.len();
+        }
+        producer.join().unwrap();
+    }
+}
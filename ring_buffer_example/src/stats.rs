@@ -0,0 +1,152 @@
+#![allow(clippy::needless_return)]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters owned by one side of the ring. Only that side writes them, so an
+/// update is a relaxed load/store pair rather than a read-modify-write, and the
+/// alignment keeps the two sides from sharing a cache line.
+#[derive(Default)]
+#[repr(align(64))]
+pub struct SideCounters {
+    pub bytes: AtomicU64,
+    pub zero_calls: AtomicU64,
+    pub triggers: AtomicU64,
+    pub wraps: AtomicU64,
+    pub high_water: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct RingCounters {
+    pub producer: SideCounters,
+    pub consumer: SideCounters,
+}
+
+/// Snapshot of the operation counters, see `RingBuf::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub bytes_produced: u64,
+    pub bytes_consumed: u64,
+    pub zero_writes: u64,
+    pub zero_reads: u64,
+    /// Publications that found the ring empty (the consumer may be waiting).
+    pub empty_triggers: u64,
+    /// Consumptions that found the ring full (the producer may be waiting).
+    pub full_triggers: u64,
+    pub space_wraps: u64,
+    pub data_wraps: u64,
+    pub high_water: u64,
+}
+
+#[inline]
+fn bump(counter: &AtomicU64, n: u64) {
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(n),
+        Ordering::Relaxed,
+    );
+}
+
+impl SideCounters {
+    #[inline]
+    pub fn on_transfer(&self, bytes: usize, trigger: bool) {
+        bump(&self.bytes, bytes as u64);
+        if trigger {
+            bump(&self.triggers, 1);
+        }
+    }
+
+    #[inline]
+    pub fn on_zero_call(&self) {
+        bump(&self.zero_calls, 1);
+    }
+
+    #[inline]
+    pub fn on_wrap(&self) {
+        bump(&self.wraps, 1);
+    }
+
+    #[inline]
+    pub fn on_occupancy(&self, used: usize) {
+        if used as u64 > self.high_water.load(Ordering::Relaxed) {
+            self.high_water.store(used as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl RingCounters {
+    pub fn snapshot(&self) -> OpStats {
+        let p = &self.producer;
+        let c = &self.consumer;
+        return OpStats {
+            bytes_produced: p.bytes.load(Ordering::Relaxed),
+            bytes_consumed: c.bytes.load(Ordering::Relaxed),
+            zero_writes: p.zero_calls.load(Ordering::Relaxed),
+            zero_reads: c.zero_calls.load(Ordering::Relaxed),
+            empty_triggers: p.triggers.load(Ordering::Relaxed),
+            full_triggers: c.triggers.load(Ordering::Relaxed),
+            space_wraps: p.wraps.load(Ordering::Relaxed),
+            data_wraps: c.wraps.load(Ordering::Relaxed),
+            high_water: p.high_water.load(Ordering::Relaxed),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ring_buffer::{RingBuf, RingMode};
+
+    #[test]
+    fn zero_copy_and_copy_paths_count_bytes_and_triggers() {
+        // 64 byte ring
+        let mut ring = RingBuf::new(8);
+        let (_, len) = ring.get_space_buf();
+        assert_eq!(len, 64);
+        assert!(ring.produce(40));
+        assert!(!ring.produce(24));
+        assert!(ring.consume(10));
+        ring.write(&[1u8; 10]).unwrap();
+        ring.write(&[1u8; 4]).unwrap();
+        let mut out = [0u8; 64];
+        ring.read(&mut out).unwrap();
+        ring.read(&mut out).unwrap();
+
+        let ops = ring.stats().ops;
+        assert_eq!((ops.bytes_produced, ops.bytes_consumed), (74, 74));
+        // the first read also found the ring full
+        assert_eq!((ops.empty_triggers, ops.full_triggers), (1, 2));
+        assert_eq!((ops.zero_writes, ops.zero_reads), (1, 1));
+        assert_eq!(ops.high_water, 64);
+    }
+
+    #[test]
+    fn stamped_totals_count_payload_on_both_sides() {
+        let mut ring = RingBuf::with_mode(8, RingMode::Stamped);
+        let (addr, len) = ring.get_space_buf();
+        assert!(addr != 0 && len >= 20);
+        ring.produce(20);
+        let (_, len) = ring.get_data_buf();
+        ring.consume(len);
+        ring.write(&[2u8; 12]).unwrap();
+        let mut out = [0u8; 64];
+        ring.read(&mut out).unwrap();
+        assert_eq!(ring.read(&mut out).unwrap(), (false, 0));
+
+        let ops = ring.stats().ops;
+        assert_eq!((ops.bytes_produced, ops.bytes_consumed), (32, 32));
+        assert_eq!(ops.zero_reads, 1);
+        // occupancy counts the 8 byte header along with the 20 byte payload
+        assert_eq!(ops.high_water, 28);
+    }
+
+    #[test]
+    fn lossy_calls_count_zero_reads_and_writes() {
+        let ring = RingBuf::with_mode(8, RingMode::Overwrite);
+        let mut out = [0u8; 64];
+        ring.write_lossy(&[]).unwrap();
+        assert_eq!(ring.read_lossy(&mut out).unwrap(), (0, 0));
+        ring.write_lossy(&[3u8; 100]).unwrap();
+        assert_eq!(ring.read_lossy(&mut out).unwrap(), (36, 64));
+
+        let ops = ring.stats().ops;
+        assert_eq!((ops.bytes_produced, ops.bytes_consumed), (100, 64));
+        assert_eq!((ops.zero_writes, ops.zero_reads), (1, 1));
+    }
+}
@@ -0,0 +1,340 @@
+#![allow(dead_code, clippy::needless_return)]
+use std::{cell::UnsafeCell, io};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Plain heap allocation; regions that cross the end of the ring come back
+    /// as two segments.
+    Heap,
+    /// The same pages mapped twice back to back, so any region of up to `len`
+    /// bytes starting inside the ring is one contiguous slice. Linux only, and
+    /// the ring size must be a multiple of the page size.
+    Mirrored,
+    /// Anonymous `mmap`, which is what huge pages, NUMA binding and `mlock`
+    /// need. Linux only; see `MapOptions`.
+    Mapped,
+}
+
+/// What to ask of the kernel for `Backing::Mapped` memory. Each request falls
+/// back quietly if the kernel or the process limits refuse it; `Placement`
+/// says what was actually granted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MapOptions {
+    /// `MAP_HUGETLB` when the size is a multiple of the huge page size, else
+    /// transparent huge pages through `madvise`.
+    pub huge_pages: bool,
+    /// Bind the pages to this NUMA node with `mbind`.
+    pub numa_node: Option<u32>,
+    /// Touch every page up front so the first writes do not fault.
+    pub prefault: bool,
+    /// `mlock` the region so it is never swapped out.
+    pub mlock: bool,
+}
+
+/// What a mapping actually got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Placement {
+    /// Backed by `MAP_HUGETLB` pages. Transparent huge pages do not count.
+    pub huge_pages: bool,
+    pub numa_node: Option<u32>,
+    pub prefaulted: bool,
+    pub locked: bool,
+}
+
+/// Memory behind a `RingBuf`.
+pub enum Storage {
+    Heap(Vec<UnsafeCell<u64>>),
+    #[cfg(target_os = "linux")]
+    Mirrored(MirroredMap),
+    #[cfg(target_os = "linux")]
+    Mapped(AnonMap),
+}
+
+impl Storage {
+    /// Allocate `count` u64 words of ring space.
+    pub fn new(count: usize, backing: Backing) -> io::Result<Self> {
+        return Self::with_options(count, backing, &MapOptions::default());
+    }
+
+    /// Like `new`; `options` only apply to `Backing::Mapped`.
+    pub fn with_options(count: usize, backing: Backing, options: &MapOptions) -> io::Result<Self> {
+        match backing {
+            Backing::Heap => Ok(Self::heap(count)),
+            #[cfg(target_os = "linux")]
+            Backing::Mirrored => Ok(Storage::Mirrored(MirroredMap::new(count * 8)?)),
+            #[cfg(target_os = "linux")]
+            Backing::Mapped => Ok(Storage::Mapped(AnonMap::new(count * 8, options)?)),
+            #[cfg(not(target_os = "linux"))]
+            Backing::Mirrored | Backing::Mapped => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    pub fn heap(count: usize) -> Self {
+        return Storage::Heap((0..count).map(|_| UnsafeCell::new(0)).collect());
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        match self {
+            Storage::Heap(v) => UnsafeCell::raw_get(v.as_ptr()) as *mut u8,
+            #[cfg(target_os = "linux")]
+            Storage::Mirrored(m) => m.ptr,
+            #[cfg(target_os = "linux")]
+            Storage::Mapped(m) => m.ptr,
+        }
+    }
+
+    /// Ring size in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            Storage::Heap(v) => v.len() * 8,
+            #[cfg(target_os = "linux")]
+            Storage::Mirrored(m) => m.len,
+            #[cfg(target_os = "linux")]
+            Storage::Mapped(m) => m.len,
+        }
+    }
+
+    /// Number of bytes addressable from `as_ptr()`, twice `len()` when mirrored.
+    #[inline]
+    pub fn mapped_len(&self) -> usize {
+        if self.is_mirrored() {
+            return self.len() * 2;
+        }
+        return self.len();
+    }
+
+    #[inline]
+    pub fn is_mirrored(&self) -> bool {
+        match self {
+            Storage::Heap(_) => false,
+            #[cfg(target_os = "linux")]
+            Storage::Mirrored(_) => true,
+            #[cfg(target_os = "linux")]
+            Storage::Mapped(_) => false,
+        }
+    }
+
+    /// What the kernel granted for mapped memory; all false for the others.
+    pub fn placement(&self) -> Placement {
+        match self {
+            #[cfg(target_os = "linux")]
+            Storage::Mapped(m) => m.placement,
+            _ => Placement::default(),
+        }
+    }
+}
+
+/// A memfd of `len` bytes mapped at `ptr` and again at `ptr + len`.
+#[cfg(target_os = "linux")]
+pub struct MirroredMap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl MirroredMap {
+    pub fn new(len: usize) -> io::Result<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        if len == 0 || !len.is_multiple_of(page) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        unsafe {
+            let fd = libc::memfd_create(c"ring_buffer".as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ftruncate(fd, len as libc::off_t) != 0 {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err);
+            }
+
+            // reserve the whole window first so nothing else can land in the second half
+            let base = libc::mmap(
+                std::ptr::null_mut(),
+                len * 2,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(err);
+            }
+
+            for half in 0..2 {
+                let addr = (base as *mut u8).add(half * len) as *mut libc::c_void;
+                let mapped = libc::mmap(
+                    addr,
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED | libc::MAP_FIXED,
+                    fd,
+                    0,
+                );
+                if mapped == libc::MAP_FAILED {
+                    let err = io::Error::last_os_error();
+                    libc::munmap(base, len * 2);
+                    libc::close(fd);
+                    return Err(err);
+                }
+            }
+
+            libc::close(fd);
+            return Ok(Self {
+                ptr: base as *mut u8,
+                len,
+            });
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for MirroredMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len * 2);
+        }
+    }
+}
+
+/// Anonymous memory of `len` bytes at `ptr`, placed as `MapOptions` asked.
+#[cfg(target_os = "linux")]
+pub struct AnonMap {
+    ptr: *mut u8,
+    /// Bytes mapped, which is `len` rounded up to the page size in use.
+    map_len: usize,
+    len: usize,
+    placement: Placement,
+}
+
+#[cfg(target_os = "linux")]
+const MPOL_BIND: libc::c_int = 2;
+
+#[cfg(target_os = "linux")]
+fn huge_page_size() -> usize {
+    let size = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|info| {
+            info.lines()
+                .find_map(|line| line.strip_prefix("Hugepagesize:"))
+                .and_then(|kb| {
+                    kb.trim()
+                        .trim_end_matches("kB")
+                        .trim()
+                        .parse::<usize>()
+                        .ok()
+                })
+        });
+    return size.map_or(2 << 20, |kb| kb * 1024);
+}
+
+#[cfg(target_os = "linux")]
+impl AnonMap {
+    /// Map `len` bytes. Only a failure of the plain mapping itself is an error;
+    /// whatever else `options` asks for is best effort.
+    pub fn new(len: usize, options: &MapOptions) -> io::Result<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut placement = Placement::default();
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+
+        let huge = huge_page_size();
+        let mut mapping = None;
+        if options.huge_pages && len.is_multiple_of(huge) {
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    prot,
+                    flags | libc::MAP_HUGETLB,
+                    -1,
+                    0,
+                )
+            };
+            // fails unless huge pages have been reserved, which is the usual case
+            if ptr != libc::MAP_FAILED {
+                placement.huge_pages = true;
+                mapping = Some((ptr, len, huge));
+            }
+        }
+        let (ptr, map_len, page) = match mapping {
+            Some(mapping) => mapping,
+            None => {
+                let map_len = len.div_ceil(page) * page;
+                let ptr = unsafe { libc::mmap(std::ptr::null_mut(), map_len, prot, flags, -1, 0) };
+                if ptr == libc::MAP_FAILED {
+                    return Err(io::Error::last_os_error());
+                }
+                if options.huge_pages {
+                    unsafe { libc::madvise(ptr, map_len, libc::MADV_HUGEPAGE) };
+                }
+                (ptr, map_len, page)
+            }
+        };
+        let map = Self {
+            ptr: ptr as *mut u8,
+            map_len,
+            len,
+            placement,
+        };
+        return Ok(map.place(options, page));
+    }
+
+    // bind, then fault in, then lock: pages must not be touched before they are bound
+    fn place(mut self, options: &MapOptions, page: usize) -> Self {
+        let addr = self.ptr as *mut libc::c_void;
+        if let Some(node) = options.numa_node {
+            let words = node as usize / 64 + 1;
+            let mut mask = vec![0u64; words];
+            mask[node as usize / 64] |= 1 << (node % 64);
+            let rc = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    addr,
+                    self.map_len,
+                    MPOL_BIND,
+                    mask.as_ptr(),
+                    words * 64 + 1,
+                    0,
+                )
+            };
+            if rc == 0 {
+                self.placement.numa_node = Some(node);
+            }
+        }
+        if options.prefault {
+            for offset in (0..self.map_len).step_by(page) {
+                // the mapping is fresh and private, so nobody can be reading it
+                unsafe { std::ptr::write_volatile(self.ptr.add(offset), 0) };
+            }
+            self.placement.prefaulted = true;
+        }
+        if options.mlock && unsafe { libc::mlock(addr, self.map_len) } == 0 {
+            self.placement.locked = true;
+        }
+        return self;
+    }
+
+    pub fn placement(&self) -> Placement {
+        return self.placement;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AnonMap {
+    fn drop(&mut self) {
+        // unmapping also drops the lock and the NUMA policy
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.map_len);
+        }
+    }
+}
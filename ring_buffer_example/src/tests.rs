@@ -0,0 +1,287 @@
+//! API coverage for `RingBuf` and `RingBufSeq`, sized so that `cargo miri test`
+//! finishes in reasonable time. The threaded cases are small on purpose: Miri's
+//! weak-memory emulation explores stale loads only for a handful of operations,
+//! so more iterations buy little.
+#![allow(clippy::needless_return)]
+
+use crate::ring_buffer::RingBuf;
+use crate::ring_buffer_seq::RingBufSeq;
+use std::{slice, thread};
+
+fn pattern(i: usize) -> u8 {
+    return (i % 251) as u8;
+}
+
+fn stream(from: usize, len: usize) -> Vec<u8> {
+    return (from..from + len).map(pattern).collect();
+}
+
+// RingBuf refuses calls its mode does not support with an error; RingBufSeq
+// has no modes and returns the value directly. The shared tests unwrap with one
+// of these, per ring type.
+fn checked<T>(res: crate::ring_buffer::Result<T>) -> T {
+    return res.unwrap();
+}
+
+fn unchecked<T>(value: T) -> T {
+    return value;
+}
+
+// Both rings expose the same API, so the single-threaded coverage is shared.
+macro_rules! api_tests {
+    ($name:ident, $ring:ty, $iovs:ident, $ok:path) => {
+        mod $name {
+            use super::*;
+            use crate::$iovs::{Iov, SocketBufIovs};
+
+            // empty 16 byte ring with head and tail both at `at`
+            fn ring_at(at: usize) -> $ring {
+                let mut ring = <$ring>::new(2);
+                ring.write(&stream(0, at)).unwrap();
+                ring.read(&mut vec![0u8; at]).unwrap();
+                return ring;
+            }
+
+            #[test]
+            fn sizes_and_raw_buf() {
+                let ring = <$ring>::new(2);
+                assert!(<$ring>::is_power_of_two(16));
+                assert!(!<$ring>::is_power_of_two(24));
+                assert!(!<$ring>::is_power_of_two(0));
+                assert_eq!(ring.len(), 16);
+                assert_eq!(ring.get_raw_buf().1, 16);
+                assert_eq!(ring.available_data_size(), 0);
+                assert_eq!(ring.available_space(), 16);
+                assert!(!ring.buf_ptr().is_null());
+                assert!(format!("{:?}", ring).starts_with("RingBuf"));
+            }
+
+            #[test]
+            fn zero_copy_round_trip_across_wrap() {
+                let ring = ring_at(11);
+                assert_eq!($ok(ring.get_read_buf()).map(|r| r.1), None);
+                assert_eq!(ring.get_data_buf(), (0, 0));
+
+                let (addr, len, more) = $ok(ring.get_write_buf()).unwrap();
+                assert_eq!((len, more), (5, true));
+                assert_eq!(ring.get_space_buf(), (addr, 5));
+                unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) }
+                    .copy_from_slice(&stream(0, 5));
+                assert!(ring.produce(5));
+
+                let (addr, len) = ring.get_space_buf();
+                assert_eq!((addr, len), (ring.buf_ptr() as u64, 11));
+                unsafe { slice::from_raw_parts_mut(addr as *mut u8, 4) }
+                    .copy_from_slice(&stream(5, 4));
+                assert!(!ring.produce_with_check(4).unwrap());
+
+                let (addr, len, more) = $ok(ring.get_read_buf()).unwrap();
+                assert_eq!((len, more), (5, true));
+                assert_eq!(ring.get_data_buf(), (addr, 5));
+                assert_eq!(
+                    unsafe { slice::from_raw_parts(addr as *const u8, len) },
+                    &stream(0, 5)[..]
+                );
+                assert!(!ring.consume_with_check(5).unwrap());
+
+                let (addr, len) = ring.get_data_buf();
+                assert_eq!(len, 4);
+                assert_eq!(
+                    unsafe { slice::from_raw_parts(addr as *const u8, len) },
+                    &stream(5, 4)[..]
+                );
+                ring.consume(4);
+                assert_eq!(ring.available_data_size(), 0);
+            }
+
+            #[test]
+            fn iovs_split_at_wrap() {
+                let ring = ring_at(13);
+                let mut iovs = [Iov { start: 0, len: 0 }; 2];
+
+                let mut space = SocketBufIovs {
+                    iovs: &mut iovs,
+                    cnt: 0,
+                };
+                $ok(ring.prepare_space_iovs(&mut space));
+                assert_eq!(space.cnt, 2);
+                assert_eq!((space.iovs[0].len, space.iovs[1].len), (3, 13));
+                let mut off = 0;
+                for iov in &space.iovs[..space.cnt] {
+                    unsafe { slice::from_raw_parts_mut(iov.start as *mut u8, iov.len) }
+                        .copy_from_slice(&stream(off, iov.len));
+                    off += iov.len;
+                }
+                ring.produce(off);
+
+                $ok(ring.prepare_space_iovs(&mut space));
+                assert_eq!(space.cnt, 0);
+
+                let mut data = SocketBufIovs {
+                    iovs: &mut iovs,
+                    cnt: 0,
+                };
+                $ok(ring.prepare_data_iovs(&mut data));
+                assert_eq!(data.cnt, 2);
+                let mut seen = Vec::new();
+                for iov in &data.iovs[..data.cnt] {
+                    seen.extend_from_slice(unsafe {
+                        slice::from_raw_parts(iov.start as *const u8, iov.len)
+                    });
+                }
+                assert_eq!(seen, stream(0, 16));
+                assert!(ring.consume(16));
+
+                $ok(ring.prepare_data_iovs(&mut data));
+                assert_eq!(data.cnt, 0);
+            }
+
+            #[test]
+            fn copy_paths_and_checks() {
+                let mut ring = ring_at(9);
+                assert_eq!(ring.write(&stream(0, 10)).unwrap(), (true, 10));
+                assert!(ring.write_full(&stream(10, 7)).is_err());
+                assert_eq!(ring.write_full(&stream(10, 6)).unwrap(), (false, 6));
+                assert!(ring.produce_with_check(1).is_err());
+
+                let mut out = [0u8; 16];
+                assert_eq!(ring.read(&mut out[..12]).unwrap(), (true, 12));
+                assert_eq!(&out[..12], &stream(0, 12)[..]);
+                assert!(ring.consume_with_check(5).is_err());
+
+                let src = stream(16, 8);
+                let (_, n) = $ok(unsafe { ring.write_via_addr(src.as_ptr() as u64, 8) });
+                assert_eq!(n, 8);
+                let (_, n) = $ok(unsafe { ring.read_via_addr(out.as_mut_ptr() as u64, 16) });
+                assert_eq!(n, 12);
+                assert_eq!(&out[..12], &stream(12, 12)[..]);
+            }
+
+            #[test]
+            fn threaded_zero_copy() {
+                let ring = <$ring>::new(2);
+                let total = 64;
+
+                let producer = ring.clone();
+                let writer = thread::spawn(move || {
+                    let mut produced = 0;
+                    while produced < total {
+                        let (addr, len) = producer.get_space_buf();
+                        let n = len.min(total - produced).min(3);
+                        if n == 0 {
+                            thread::yield_now();
+                            continue;
+                        }
+                        unsafe { slice::from_raw_parts_mut(addr as *mut u8, n) }
+                            .copy_from_slice(&stream(produced, n));
+                        producer.produce(n);
+                        produced += n;
+                    }
+                });
+
+                let mut consumed = 0;
+                while consumed < total {
+                    let (addr, len) = ring.get_data_buf();
+                    if len == 0 {
+                        thread::yield_now();
+                        continue;
+                    }
+                    let data = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+                    assert_eq!(data, &stream(consumed, len)[..]);
+                    ring.consume(len);
+                    consumed += len;
+                }
+                writer.join().unwrap();
+            }
+
+            #[test]
+            fn threaded_copy() {
+                let ring = <$ring>::new(2);
+                let total = 64;
+
+                let mut producer = ring.clone();
+                let writer = thread::spawn(move || {
+                    let mut written = 0;
+                    while written < total {
+                        let n = (total - written).min(5);
+                        let (_, n) = producer.write(&stream(written, n)).unwrap();
+                        if n == 0 {
+                            thread::yield_now();
+                        }
+                        written += n;
+                    }
+                });
+
+                let mut out = [0u8; 7];
+                let mut read = 0;
+                while read < total {
+                    let (_, n) = ring.read(&mut out).unwrap();
+                    if n == 0 {
+                        thread::yield_now();
+                        continue;
+                    }
+                    assert_eq!(&out[..n], &stream(read, n)[..]);
+                    read += n;
+                }
+                writer.join().unwrap();
+            }
+        }
+    };
+}
+
+api_tests!(ring_buf, RingBuf, ring_buffer, checked);
+api_tests!(ring_buf_seq, RingBufSeq, ring_buffer_seq, unchecked);
+
+mod ring_buf_only {
+    use super::*;
+    use crate::ring_buffer::{RingMode, Side};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn peek_skip_and_lossy() {
+        let mut ring = RingBuf::new(2);
+        let mut out = [0u8; 16];
+        ring.write(&stream(0, 12)).unwrap();
+        assert_eq!(ring.peek(&mut out[..4]).unwrap(), 4);
+        assert_eq!(ring.peek_at(10, &mut out).unwrap(), 2);
+        assert_eq!(&out[..2], &stream(10, 2)[..]);
+        assert_eq!(ring.skip(20).unwrap(), 12);
+
+        let lossy = RingBuf::with_mode(2, RingMode::Overwrite);
+        lossy.write_lossy(&stream(0, 20)).unwrap();
+        assert_eq!(lossy.read_lossy(&mut out).unwrap(), (4, 16));
+        assert_eq!(&out[..], &stream(4, 16)[..]);
+    }
+
+    #[test]
+    fn stats_and_watchdog() {
+        let mut ring = RingBuf::new(2);
+        ring.write(&stream(0, 16)).unwrap();
+        ring.set_parked(Side::Producer, true);
+
+        let (tx, rx) = mpsc::channel();
+        let watchdog = ring.watchdog(Duration::from_millis(5), move |stats| {
+            let _ = tx.send(*stats);
+        });
+        let stats = rx.recv().unwrap();
+        drop(watchdog);
+
+        assert_eq!(stats.available_data, 16);
+        assert_eq!(stats.available_space, 0);
+        assert!(stats.producer_parked);
+        assert!(!stats.consumer_parked);
+        assert_eq!(stats, ring.stats());
+    }
+
+    #[test]
+    fn handles_round_trip() {
+        let (mut producer, consumer) = RingBuf::new(2).split();
+        assert_eq!(producer.write(&stream(0, 20)).unwrap(), 16);
+        let mut out = [0u8; 16];
+        assert_eq!(consumer.read(&mut out).unwrap(), 16);
+        producer.close();
+        assert_eq!(consumer.read(&mut out).unwrap(), 0);
+        assert!(consumer.is_eof());
+    }
+}
@@ -0,0 +1,206 @@
+#![allow(dead_code, clippy::needless_return)]
+use crate::handle::{Consumer, Producer};
+use crate::ring_buffer::{Error, Iov, Result, SocketBufIovs};
+use std::ptr;
+
+fn data_iovs(src: &Consumer) -> Result<[Iov; 2]> {
+    let mut iovs = [Iov { start: 0, len: 0 }; 2];
+    let mut data = SocketBufIovs {
+        iovs: &mut iovs,
+        cnt: 0,
+    };
+    src.ring().prepare_data_iovs(&mut data)?;
+    return Ok(iovs);
+}
+
+fn space_iovs(dst: &Producer) -> Result<[Iov; 2]> {
+    let mut iovs = [Iov { start: 0, len: 0 }; 2];
+    let mut space = SocketBufIovs {
+        iovs: &mut iovs,
+        cnt: 0,
+    };
+    dst.ring().prepare_space_iovs(&mut space)?;
+    return Ok(iovs);
+}
+
+fn total(iovs: &[Iov]) -> usize {
+    return iovs.iter().map(|iov| iov.len).sum();
+}
+
+/// Copy the first `count` bytes of `from` into `to`, walking both segment
+/// lists at once so either side may wrap anywhere.
+unsafe fn copy_segments(from: &[Iov], to: &[Iov], count: usize) {
+    let (mut fi, mut foff) = (0, 0);
+    let (mut ti, mut toff) = (0, 0);
+    let mut left = count;
+    while left > 0 {
+        let n = left.min(from[fi].len - foff).min(to[ti].len - toff);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                (from[fi].start as usize + foff) as *const u8,
+                (to[ti].start as usize + toff) as *mut u8,
+                n,
+            );
+        }
+        left -= n;
+        foff += n;
+        toff += n;
+        if foff == from[fi].len {
+            fi += 1;
+            foff = 0;
+        }
+        if toff == to[ti].len {
+            ti += 1;
+            toff = 0;
+        }
+    }
+}
+
+/// Move up to `max` bytes from `src` to `dst`, straight from one ring's data to
+/// the other's free space. Returns how many bytes moved. Fails with
+/// `Error::Unsupported` if either ring is stamped, and with `Error::Closed` if
+/// `dst` is closed.
+pub fn transfer(src: &Consumer, dst: &Producer, max: usize) -> Result<usize> {
+    return tee_all(src, &[dst], max);
+}
+
+/// Copy up to `max` bytes from `src` into both `a` and `b`, then release them
+/// from `src`. Limited by whichever destination has less room. Fails as
+/// `transfer` does, if either destination is closed.
+pub fn tee(src: &Consumer, a: &Producer, b: &Producer, max: usize) -> Result<usize> {
+    return tee_all(src, &[a, b], max);
+}
+
+fn tee_all(src: &Consumer, dsts: &[&Producer], max: usize) -> Result<usize> {
+    // as `Producer::try_write` does, stop feeding a ring nobody reads
+    if dsts.iter().any(|dst| dst.is_closed()) {
+        return Err(Error::Closed);
+    }
+    // the iovs come from the rings, so anything the handles deferred goes first
+    src.flush();
+    for dst in dsts {
+        dst.flush();
+    }
+
+    let from = data_iovs(src)?;
+    let spaces = dsts
+        .iter()
+        .map(|dst| space_iovs(dst))
+        .collect::<Result<Vec<_>>>()?;
+    let count = spaces
+        .iter()
+        .map(|to| total(to))
+        .fold(total(&from).min(max), usize::min);
+    if count == 0 {
+        return Ok(0);
+    }
+
+    for (dst, to) in dsts.iter().zip(&spaces) {
+        // distinct rings, and the segments come from their own data/space regions
+        unsafe { copy_segments(&from, to, count) };
+        dst.produce(count)?;
+    }
+    src.consume(count)?;
+    return Ok(count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ring_buffer::{Error, RingBuf, RingMode};
+
+    // 16 byte ring split into handles, with head and tail both at `at`
+    fn handles_at(at: usize) -> (Producer, Consumer) {
+        let mut ring = RingBuf::new(2);
+        ring.write(&vec![0xee; at]).unwrap();
+        ring.skip(at).unwrap();
+        return ring.split();
+    }
+
+    fn drain(consumer: &Consumer) -> Vec<u8> {
+        let mut out = vec![0u8; 16];
+        let n = consumer.try_read(&mut out).unwrap().1;
+        out.truncate(n);
+        return out;
+    }
+
+    #[test]
+    fn transfer_handles_every_wrap_combination() {
+        let data: Vec<u8> = (1..=12).collect();
+        // (source position, destination position): neither wraps, source wraps,
+        // destination wraps, both wrap at different points
+        for (src_at, dst_at) in [(0, 2), (10, 0), (0, 9), (7, 12)] {
+            let (mut sp, sc) = handles_at(src_at);
+            let (dp, dc) = handles_at(dst_at);
+            sp.try_write(&data).unwrap();
+
+            assert_eq!(
+                transfer(&sc, &dp, usize::MAX).unwrap(),
+                12,
+                "{src_at} -> {dst_at}"
+            );
+            assert_eq!(sc.ring().available_data_size(), 0);
+            assert_eq!(drain(&dc), data, "{src_at} -> {dst_at}");
+        }
+    }
+
+    #[test]
+    fn stamped_rings_are_refused() {
+        let (mut sp, sc) = RingBuf::with_mode(2, RingMode::Stamped).split();
+        let (dp, _dc) = handles_at(0);
+        sp.try_write(&[1u8; 4]).unwrap();
+        assert!(matches!(
+            transfer(&sc, &dp, usize::MAX),
+            Err(Error::Unsupported {
+                mode: RingMode::Stamped,
+                ..
+            })
+        ));
+        assert_eq!(sc.ring().available_data_size(), 12);
+    }
+
+    #[test]
+    fn closed_destinations_are_refused() {
+        let (mut sp, sc) = handles_at(0);
+        let (ap, ac) = handles_at(0);
+        let (bp, bc) = handles_at(0);
+        sp.try_write(&[1u8; 4]).unwrap();
+        drop(bc);
+        assert!(matches!(tee(&sc, &ap, &bp, usize::MAX), Err(Error::Closed)));
+        assert_eq!(ac.ring().available_data_size(), 0);
+        assert_eq!(transfer(&sc, &ap, usize::MAX).unwrap(), 4);
+        ap.close();
+        assert!(matches!(transfer(&sc, &ap, usize::MAX), Err(Error::Closed)));
+    }
+
+    #[test]
+    fn transfer_stops_at_max_and_free_space() {
+        let (mut sp, sc) = handles_at(5);
+        let (mut dp, dc) = handles_at(3);
+        sp.try_write(&[7u8; 16]).unwrap();
+        dp.try_write(&[1u8; 10]).unwrap();
+
+        assert_eq!(transfer(&sc, &dp, 4).unwrap(), 4);
+        assert_eq!(transfer(&sc, &dp, usize::MAX).unwrap(), 2);
+        assert_eq!(transfer(&sc, &dp, usize::MAX).unwrap(), 0);
+        assert_eq!(sc.ring().available_data_size(), 10);
+        assert_eq!(drain(&dc)[10..], [7u8; 6]);
+    }
+
+    #[test]
+    fn tee_copies_to_both_destinations() {
+        let (mut sp, sc) = handles_at(14);
+        let (ap, ac) = handles_at(0);
+        let (mut bp, bc) = handles_at(9);
+        let data: Vec<u8> = (0..10).collect();
+        sp.try_write(&data).unwrap();
+        bp.try_write(&[9u8; 8]).unwrap();
+
+        // b has room for 8 only
+        assert_eq!(tee(&sc, &ap, &bp, usize::MAX).unwrap(), 8);
+        assert_eq!(drain(&ac), data[..8]);
+        assert_eq!(drain(&bc)[8..], data[..8]);
+        assert_eq!(tee(&sc, &ap, &bp, usize::MAX).unwrap(), 2);
+        assert_eq!(drain(&ac), data[8..]);
+    }
+}
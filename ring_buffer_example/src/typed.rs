@@ -0,0 +1,391 @@
+//! Typed messages over a ring, for callers that would otherwise hand-roll
+//! serialization on top of `write`.
+//!
+//! Each message is a frame: a little-endian `u32` length, then the encoded
+//! value. The sender encodes straight into the free space when the frame fits
+//! before the end of the ring, and publishes the whole frame at once, so the
+//! receiver never sees half a message. The receiver decodes in place, letting
+//! borrowed types such as `&str` point into ring memory; only frames that wrap
+//! around a heap-backed ring are copied out first. A mirrored ring never wraps.
+#![allow(dead_code, clippy::needless_return)]
+use crate::handle::{Consumer, Producer};
+use crate::ring_buffer::{Error, Result, RingBuf, Side};
+use serde::Serialize;
+use serde::de::{Deserialize, DeserializeOwned};
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::slice;
+
+const HEADER: usize = 4;
+
+/// How values become bytes in the ring.
+pub trait Codec {
+    fn encoded_len<T: Serialize + ?Sized>(&self, value: &T) -> Result<usize>;
+    /// Encode `value` into exactly `encoded_len(value)` bytes of `out`.
+    fn encode<T: Serialize + ?Sized>(&self, value: &T, out: &mut [u8]) -> Result<()>;
+    fn decode<'de, T: Deserialize<'de>>(&self, bytes: &'de [u8]) -> Result<T>;
+}
+
+/// Compact binary encoding (bincode 1, fixed-width integers).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encoded_len<T: Serialize + ?Sized>(&self, value: &T) -> Result<usize> {
+        return Ok(bincode::serialized_size(value).map_err(codec_error)? as usize);
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T, out: &mut [u8]) -> Result<()> {
+        return bincode::serialize_into(out, value).map_err(codec_error);
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(&self, bytes: &'de [u8]) -> Result<T> {
+        return bincode::deserialize(bytes).map_err(codec_error);
+    }
+}
+
+/// JSON, for peers that want to read the traffic. Borrowed strings only decode
+/// in place when they contain no escapes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+/// Counts what would be written, so JSON can be sized before it is reserved.
+struct Counter(usize);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Codec for Json {
+    fn encoded_len<T: Serialize + ?Sized>(&self, value: &T) -> Result<usize> {
+        let mut counter = Counter(0);
+        serde_json::to_writer(&mut counter, value).map_err(codec_error)?;
+        return Ok(counter.0);
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T, out: &mut [u8]) -> Result<()> {
+        return serde_json::to_writer(out, value).map_err(codec_error);
+    }
+
+    fn decode<'de, T: Deserialize<'de>>(&self, bytes: &'de [u8]) -> Result<T> {
+        return serde_json::from_slice(bytes).map_err(codec_error);
+    }
+}
+
+fn codec_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> Error {
+    return Error::Codec(Box::new(err));
+}
+
+/// A ring carrying values of type `T`, encoded with `C`.
+pub struct TypedChannel<T, C = Bincode> {
+    ring: RingBuf,
+    codec: C,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned, C: Codec + Clone> TypedChannel<T, C> {
+    pub fn new(ring: RingBuf, codec: C) -> Self {
+        return Self {
+            ring,
+            codec,
+            _marker: PhantomData,
+        };
+    }
+
+    /// Largest encoded message the ring can carry.
+    pub fn max_message_len(&self) -> usize {
+        return self.ring.len() - HEADER;
+    }
+
+    pub fn split(self) -> (TypedSender<T, C>, TypedReceiver<T, C>) {
+        let (producer, consumer) = self.ring.split();
+        let sender = TypedSender {
+            producer,
+            codec: self.codec.clone(),
+            scratch: Vec::new(),
+            _marker: PhantomData,
+        };
+        let receiver = TypedReceiver {
+            consumer,
+            codec: self.codec,
+            scratch: Vec::new(),
+            offset: 0,
+            seq: 0,
+            _marker: PhantomData,
+        };
+        return (sender, receiver);
+    }
+}
+
+pub struct TypedSender<T, C = Bincode> {
+    producer: Producer,
+    codec: C,
+    /// Frames that would wrap are encoded here and copied in two parts.
+    scratch: Vec<u8>,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize, C: Codec> TypedSender<T, C> {
+    pub fn producer(&self) -> &Producer {
+        return &self.producer;
+    }
+
+    fn frame_len(&self, value: &T) -> Result<usize> {
+        let frame = HEADER + self.codec.encoded_len(value)?;
+        let capacity = self.producer.ring().len();
+        if frame > capacity {
+            return Err(Error::TooLarge {
+                len: frame,
+                capacity,
+            });
+        }
+        return Ok(frame);
+    }
+
+    /// Send `value` if the ring has room for the whole frame right now.
+    /// Fails with `Error::TooLarge` if it could never fit.
+    pub fn try_send(&mut self, value: &T) -> Result<bool> {
+        if self.producer.is_closed() {
+            return Err(Error::Closed);
+        }
+        let frame = self.frame_len(value)?;
+        let (addr, len) = self.producer.get_space_buf()?;
+        if len >= frame {
+            // the frame lies in space the consumer cannot read until commit
+            let out = unsafe { slice::from_raw_parts_mut(addr as *mut u8, frame) };
+            self.codec.encode(value, &mut out[HEADER..])?;
+            out[..HEADER].copy_from_slice(&((frame - HEADER) as u32).to_le_bytes());
+            self.producer.commit(frame)?;
+            return Ok(true);
+        }
+
+        self.producer.flush();
+        if self.producer.ring().available_space() < frame {
+            return Ok(false);
+        }
+        self.scratch.resize(frame, 0);
+        self.codec.encode(value, &mut self.scratch[HEADER..])?;
+        self.scratch[..HEADER].copy_from_slice(&((frame - HEADER) as u32).to_le_bytes());
+        self.producer.try_write(&self.scratch)?;
+        return Ok(true);
+    }
+
+    /// Send `value`, waiting until there is room for it.
+    pub fn send(&mut self, value: &T) -> Result<()> {
+        let frame = self.frame_len(value)?;
+        while !self.try_send(value)? {
+            self.producer
+                .ring()
+                .wait_until(Side::Producer, |ring| ring.available_space() >= frame);
+        }
+        return Ok(());
+    }
+}
+
+pub struct TypedReceiver<T, C = Bincode> {
+    consumer: Consumer,
+    codec: C,
+    /// Frames that wrap are copied here to be decoded.
+    scratch: Vec<u8>,
+    /// Stream offset and index of the next frame, for error reports.
+    offset: u64,
+    seq: u32,
+    _marker: PhantomData<fn() -> T>,
+}
+
+/// A message decoded in place. It may borrow from ring memory, so the frame is
+/// released only when this is dropped.
+pub struct Received<'a, U> {
+    value: Option<U>,
+    consumer: &'a Consumer,
+    frame: usize,
+}
+
+impl<U> Received<'_, U> {
+    pub fn into_inner(mut self) -> U {
+        return self.value.take().unwrap();
+    }
+}
+
+impl<U> Deref for Received<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        return self.value.as_ref().unwrap();
+    }
+}
+
+impl<U> Drop for Received<'_, U> {
+    fn drop(&mut self) {
+        // get_data_buf accepted this ring, so its commit cannot be refused
+        let _ = self.consumer.commit(self.frame);
+    }
+}
+
+impl<T: DeserializeOwned, C: Codec> TypedReceiver<T, C> {
+    pub fn consumer(&self) -> &Consumer {
+        return &self.consumer;
+    }
+
+    fn corrupted(&self) -> Error {
+        return Error::Corrupted {
+            offset: self.offset,
+            expected_seq: self.seq,
+        };
+    }
+
+    /// Decode the next message as `U`, which may borrow from the ring, e.g. a
+    /// `&str` view of a `String` field. `None` if no message is waiting.
+    /// A frame that fails to decode is dropped and reported as `Error::Codec`.
+    pub fn try_recv_ref<'a, U: Deserialize<'a>>(&'a mut self) -> Result<Option<Received<'a, U>>> {
+        let mut header = [0u8; HEADER];
+        if self.consumer.peek(&mut header)? < HEADER {
+            return Ok(None);
+        }
+        let frame = HEADER + u32::from_le_bytes(header) as usize;
+        if frame > self.consumer.ring().len() {
+            return Err(self.corrupted());
+        }
+
+        let (addr, len) = self.consumer.get_data_buf()?;
+        let bytes: &'a [u8] = if len >= frame {
+            // committed data stays put until the `Received` is dropped
+            unsafe { slice::from_raw_parts((addr as *const u8).add(HEADER), frame - HEADER) }
+        } else {
+            self.scratch.resize(frame, 0);
+            if self.consumer.peek(&mut self.scratch)? < frame {
+                // frames are published whole, so this only happens on a torn ring
+                return Err(self.corrupted());
+            }
+            &self.scratch[HEADER..]
+        };
+
+        // the frame is released whether or not it decodes
+        self.offset += frame as u64;
+        self.seq = self.seq.wrapping_add(1);
+        let value = match self.codec.decode(bytes) {
+            Ok(value) => value,
+            Err(err) => {
+                self.consumer.commit(frame)?;
+                return Err(err);
+            }
+        };
+        return Ok(Some(Received {
+            value: Some(value),
+            consumer: &self.consumer,
+            frame,
+        }));
+    }
+
+    /// Take the next message, if one is waiting.
+    pub fn try_recv(&mut self) -> Result<Option<T>> {
+        return Ok(self.try_recv_ref::<T>()?.map(Received::into_inner));
+    }
+
+    /// Wait for the next message. `None` once the sender has closed and every
+    /// message has been received.
+    pub fn recv(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(value) = self.try_recv()? {
+                return Ok(Some(value));
+            }
+            if self.consumer.is_eof() {
+                return Ok(None);
+            }
+            // release what was read so the wait below sees only new data
+            self.consumer.flush();
+            self.consumer.wait_data();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn binary_messages_decode_in_place_and_across_the_wrap() {
+        let (mut tx, mut rx) =
+            TypedChannel::<(u32, String), _>::new(RingBuf::new(8), Bincode).split();
+        assert!(rx.try_recv().unwrap().is_none());
+
+        // 4 + 4 + 8 + 10 = 26 bytes a frame, so the third one wraps the 64 byte ring
+        assert!(tx.try_send(&(0, "message 00".to_string())).unwrap());
+        assert!(tx.try_send(&(1, "message 01".to_string())).unwrap());
+        assert!(!tx.try_send(&(2, "message 02".to_string())).unwrap());
+        {
+            let msg = rx.try_recv_ref::<(u32, &str)>().unwrap().unwrap();
+            assert_eq!(*msg, (0, "message 00"));
+            let start = tx.producer().ring().buf_ptr() as usize;
+            let at = msg.1.as_ptr() as usize;
+            assert!(at >= start && at < start + 64, "borrowed from the ring");
+        }
+        assert!(tx.try_send(&(2, "message 02".to_string())).unwrap());
+
+        assert_eq!(rx.try_recv().unwrap(), Some((1, "message 01".to_string())));
+        let msg = rx.try_recv_ref::<(u32, &str)>().unwrap().unwrap();
+        assert_eq!(*msg, (2, "message 02"));
+        drop(msg);
+        assert!(rx.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn json_messages_round_trip() {
+        let (mut tx, mut rx) = TypedChannel::<Vec<u16>, _>::new(RingBuf::new(8), Json).split();
+        for n in 0..20u16 {
+            tx.send(&(0..n % 4).collect()).unwrap();
+            let expected: Vec<u16> = (0..n % 4).collect();
+            assert_eq!(rx.try_recv().unwrap(), Some(expected));
+        }
+        tx.send(&vec![1, 2]).unwrap();
+        let raw = rx.try_recv_ref::<serde_json::Value>().unwrap().unwrap();
+        assert_eq!(raw.to_string(), "[1,2]");
+    }
+
+    #[test]
+    fn oversized_and_malformed_messages_are_reported() {
+        let (mut tx, mut rx) = TypedChannel::<String, _>::new(RingBuf::new(2), Bincode).split();
+        match tx.try_send(&"x".repeat(16)) {
+            Err(Error::TooLarge { len, capacity }) => assert_eq!((len, capacity), (28, 16)),
+            other => panic!("expected TooLarge, got {:?}", other),
+        }
+
+        // a frame that is not valid UTF-8 is dropped, and the next one still arrives
+        tx.producer()
+            .ring()
+            .clone()
+            .write(&[3, 0, 0, 0, 0xff, 0xfe, 0xfd])
+            .unwrap();
+        assert!(matches!(rx.try_recv(), Err(Error::Codec(_))));
+        tx.try_send(&"ok".to_string()).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), Some("ok".to_string()));
+    }
+
+    #[test]
+    fn streams_between_threads_until_close() {
+        let (mut tx, mut rx) =
+            TypedChannel::<(u64, Vec<u8>), _>::new(RingBuf::new(16), Bincode).split();
+        let writer = thread::spawn(move || {
+            for i in 0..2000u64 {
+                tx.send(&(i, vec![i as u8; (i % 40) as usize])).unwrap();
+            }
+        });
+        let mut next = 0u64;
+        while let Some((i, body)) = rx.recv().unwrap() {
+            assert_eq!(i, next);
+            assert_eq!(body, vec![i as u8; (i % 40) as usize]);
+            next += 1;
+        }
+        writer.join().unwrap();
+        assert_eq!(next, 2000);
+    }
+}
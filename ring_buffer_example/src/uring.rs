@@ -0,0 +1,411 @@
+//! io_uring transfers straight between file descriptors and ring memory.
+//!
+//! `UringIo` registers the ring's storage as fixed buffer 0 and turns the
+//! `Iov`s from `prepare_space_iovs`/`prepare_data_iovs` into `READ_FIXED` and
+//! `WRITE_FIXED` requests. A receive and a send can be in flight at once and
+//! go out with one `io_uring_enter`; completions call `produce`/`consume`, so
+//! a socket-to-ring pipeline moves no bytes through user space.
+#![allow(dead_code, clippy::needless_return)]
+use crate::ring_buffer::{Iov, RingBuf, SocketBufIovs};
+use io_uring::{IoUring, opcode, squeue, types};
+use std::io;
+use std::os::fd::RawFd;
+
+const RECV: u64 = 0;
+const SEND: u64 = 1;
+
+/// Read or write on the current file position, which is what sockets and pipes want.
+const CURRENT_POS: u64 = u64::MAX;
+
+/// One side of a transfer: up to two linked requests, settled together.
+#[derive(Default)]
+struct InFlight {
+    parts: usize,
+    done: usize,
+    bytes: usize,
+    /// A part finished short, so the bytes after it never arrived.
+    short: bool,
+    error: Option<io::Error>,
+}
+
+impl InFlight {
+    fn is_idle(&self) -> bool {
+        return self.parts == 0;
+    }
+
+    fn settle(&mut self, len: usize, res: i32) {
+        self.done += 1;
+        if res < 0 {
+            // the second part is cancelled when the first fails or comes up short
+            if res != -libc::ECANCELED && self.error.is_none() {
+                self.error = Some(io::Error::from_raw_os_error(-res));
+            }
+            self.short = true;
+            return;
+        }
+        // linked parts complete in order, so nothing after a short part is counted
+        if !self.short {
+            self.bytes += res as usize;
+        }
+        if (res as usize) < len {
+            self.short = true;
+        }
+    }
+}
+
+/// Outcome of one `complete` call. `None` means that side was not finished.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Completions {
+    /// Bytes received and produced; `Some(0)` is end of file.
+    pub received: Option<usize>,
+    /// Bytes sent and consumed.
+    pub sent: Option<usize>,
+}
+
+impl Completions {
+    fn is_empty(&self) -> bool {
+        return self.received.is_none() && self.sent.is_none();
+    }
+
+    // add `other` to what is already counted for each side
+    fn merge(&mut self, other: Completions) {
+        let sum = |a: Option<usize>, b: Option<usize>| {
+            return match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            };
+        };
+        self.received = sum(self.received, other.received);
+        self.sent = sum(self.sent, other.sent);
+    }
+}
+
+pub struct UringIo {
+    uring: IoUring,
+    ring: RingBuf,
+    recv: InFlight,
+    send: InFlight,
+    recv_lens: [usize; 2],
+    send_lens: [usize; 2],
+    /// Transfers already settled and published but not reported yet, because
+    /// the call that reaped them failed or was waiting for the other side.
+    unreported: Completions,
+}
+
+impl UringIo {
+    /// Set up a submission queue of `entries` and register `ring`'s memory.
+    /// Fails if the kernel lacks io_uring or refuses to pin the buffer.
+    pub fn new(ring: RingBuf, entries: u32) -> io::Result<Self> {
+        let uring = IoUring::new(entries.max(4))?;
+        let (storage, _) = ring.get_raw_buf();
+        let iov = libc::iovec {
+            iov_base: storage.as_ptr().cast(),
+            iov_len: storage.mapped_len(),
+        };
+        // the ring keeps the storage alive for as long as `uring` exists
+        unsafe { uring.submitter().register_buffers(&[iov])? };
+        return Ok(Self {
+            uring,
+            ring,
+            recv: InFlight::default(),
+            send: InFlight::default(),
+            recv_lens: [0; 2],
+            send_lens: [0; 2],
+            unreported: Completions::default(),
+        });
+    }
+
+    pub fn ring(&self) -> &RingBuf {
+        return &self.ring;
+    }
+
+    /// Queue a read from `fd` into the free space. Acts as the ring's producer.
+    /// Returns false if a receive is already queued or the ring is full.
+    pub fn queue_recv(&mut self, fd: RawFd) -> io::Result<bool> {
+        if !self.recv.is_idle() {
+            return Ok(false);
+        }
+        let mut iovs = [Iov { start: 0, len: 0 }; 2];
+        let mut space = SocketBufIovs {
+            iovs: &mut iovs,
+            cnt: 0,
+        };
+        self.ring
+            .prepare_space_iovs(&mut space)
+            .map_err(io::Error::other)?;
+        let entries: Vec<_> = space.iovs[..space.cnt]
+            .iter()
+            .map(|iov| {
+                opcode::ReadFixed::new(types::Fd(fd), iov.start as *mut u8, iov.len as u32, 0)
+                    .offset(CURRENT_POS)
+                    .build()
+            })
+            .collect();
+        for (i, iov) in space.iovs[..space.cnt].iter().enumerate() {
+            self.recv_lens[i] = iov.len;
+        }
+        self.push(RECV, entries)?;
+        self.recv = InFlight {
+            parts: space.cnt,
+            ..Default::default()
+        };
+        return Ok(space.cnt > 0);
+    }
+
+    /// Queue a write of the buffered data to `fd`. Acts as the ring's consumer.
+    /// Returns false if a send is already queued or the ring is empty.
+    pub fn queue_send(&mut self, fd: RawFd) -> io::Result<bool> {
+        if !self.send.is_idle() {
+            return Ok(false);
+        }
+        let mut iovs = [Iov { start: 0, len: 0 }; 2];
+        let mut data = SocketBufIovs {
+            iovs: &mut iovs,
+            cnt: 0,
+        };
+        self.ring
+            .prepare_data_iovs(&mut data)
+            .map_err(io::Error::other)?;
+        let entries: Vec<_> = data.iovs[..data.cnt]
+            .iter()
+            .map(|iov| {
+                opcode::WriteFixed::new(types::Fd(fd), iov.start as *const u8, iov.len as u32, 0)
+                    .offset(CURRENT_POS)
+                    .build()
+            })
+            .collect();
+        for (i, iov) in data.iovs[..data.cnt].iter().enumerate() {
+            self.send_lens[i] = iov.len;
+        }
+        self.push(SEND, entries)?;
+        self.send = InFlight {
+            parts: data.cnt,
+            ..Default::default()
+        };
+        return Ok(data.cnt > 0);
+    }
+
+    fn push(&mut self, side: u64, entries: Vec<squeue::Entry>) -> io::Result<()> {
+        let last = entries.len().saturating_sub(1);
+        let mut sq = self.uring.submission();
+        for (part, entry) in entries.into_iter().enumerate() {
+            // link the two halves of a wrapped region so they complete in order
+            let flags = if part < last {
+                squeue::Flags::IO_LINK
+            } else {
+                squeue::Flags::empty()
+            };
+            let entry = entry.flags(flags).user_data(side << 1 | part as u64);
+            // the buffers point into registered ring memory, which outlives the request
+            unsafe { sq.push(&entry) }.map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        }
+        return Ok(());
+    }
+
+    /// Submit everything queued, wait until `want` requests have completed, and
+    /// publish whatever transfers have fully settled. Transfers left unreported
+    /// by an earlier call are returned too, without waiting. If a side failed,
+    /// the other side's outcome is kept for the next call.
+    pub fn complete(&mut self, want: usize) -> io::Result<Completions> {
+        let want = if self.unreported.is_empty() { want } else { 0 };
+        self.uring.submit_and_wait(want)?;
+        let cqes: Vec<_> = self.uring.completion().collect();
+        for cqe in cqes {
+            let (side, part) = (cqe.user_data() >> 1, (cqe.user_data() & 1) as usize);
+            if side == RECV {
+                self.recv.settle(self.recv_lens[part], cqe.result());
+            } else {
+                self.send.settle(self.send_lens[part], cqe.result());
+            }
+        }
+
+        // settle both sides before reporting an error, so neither is dropped
+        let mut out = std::mem::take(&mut self.unreported);
+        let mut error = None;
+        if !self.recv.is_idle() && self.recv.done == self.recv.parts {
+            let recv = std::mem::take(&mut self.recv);
+            match recv.error.filter(|_| recv.bytes == 0) {
+                Some(err) => error = Some(err),
+                None => {
+                    if recv.bytes > 0 {
+                        self.ring.produce(recv.bytes);
+                    }
+                    out.merge(Completions {
+                        received: Some(recv.bytes),
+                        sent: None,
+                    });
+                }
+            }
+        }
+        if !self.send.is_idle() && self.send.done == self.send.parts {
+            let send = std::mem::take(&mut self.send);
+            match send.error.filter(|_| send.bytes == 0) {
+                Some(err) => error = error.or(Some(err)),
+                None => {
+                    if send.bytes > 0 {
+                        self.ring.consume(send.bytes);
+                    }
+                    out.merge(Completions {
+                        received: None,
+                        sent: Some(send.bytes),
+                    });
+                }
+            }
+        }
+        if let Some(err) = error {
+            self.unreported = out;
+            return Err(err);
+        }
+        return Ok(out);
+    }
+
+    // wait until the side `pick` selects settles; the other side's outcome is
+    // kept for the next `complete`
+    fn wait_for(&mut self, pick: fn(&mut Completions) -> &mut Option<usize>) -> io::Result<usize> {
+        let mut later = std::mem::take(&mut self.unreported);
+        loop {
+            let mut done = match self.complete(1) {
+                Ok(done) => done,
+                Err(err) => {
+                    self.unreported.merge(later);
+                    return Err(err);
+                }
+            };
+            let mine = pick(&mut done).take();
+            later.merge(done);
+            if let Some(n) = mine {
+                self.unreported = later;
+                return Ok(n);
+            }
+        }
+    }
+
+    /// Read once from `fd` into the ring and wait for it. 0 is end of file, or
+    /// a full ring. A send that settles meanwhile is left for `complete`.
+    pub fn recv(&mut self, fd: RawFd) -> io::Result<usize> {
+        if !self.queue_recv(fd)? {
+            return Ok(0);
+        }
+        return self.wait_for(|done| &mut done.received);
+    }
+
+    /// Write buffered data to `fd` once and wait for it. A receive that
+    /// settles meanwhile is left for `complete`.
+    pub fn send(&mut self, fd: RawFd) -> io::Result<usize> {
+        if !self.queue_send(fd)? {
+            return Ok(0);
+        }
+        return self.wait_for(|done| &mut done.sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        return unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    }
+
+    fn write_all(fd: &OwnedFd, buf: &[u8]) {
+        let n = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+        assert_eq!(n as usize, buf.len());
+    }
+
+    fn read_some(fd: &OwnedFd, buf: &mut [u8]) -> usize {
+        let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        assert!(n >= 0);
+        return n as usize;
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "io_uring is not modelled")]
+    fn pipe_to_ring_to_pipe_across_wrap() {
+        let ring = RingBuf::new(2);
+        let mut io = match UringIo::new(ring.clone(), 8) {
+            Ok(io) => io,
+            Err(err) => {
+                eprintln!("skipping: io_uring unavailable: {}", err);
+                return;
+            }
+        };
+        let (src_r, src_w) = pipe();
+        let (dst_r, dst_w) = pipe();
+
+        // move head and tail to 11 so the free space wraps
+        let mut mover = ring.clone();
+        mover.write(&[0u8; 11]).unwrap();
+        ring.skip(11).unwrap();
+
+        let data: Vec<u8> = (0..16).collect();
+        write_all(&src_w, &data);
+        assert_eq!(io.recv(src_r.as_raw_fd()).unwrap(), 16);
+        assert_eq!(ring.available_data_size(), 16);
+        assert_eq!(io.recv(src_r.as_raw_fd()).unwrap(), 0);
+
+        // the ring is full: only the send goes out
+        write_all(&src_w, &data[..4]);
+        assert!(!io.queue_recv(src_r.as_raw_fd()).unwrap());
+        assert!(io.queue_send(dst_w.as_raw_fd()).unwrap());
+        let mut sent = None;
+        while sent.is_none() {
+            sent = io.complete(1).unwrap().sent;
+        }
+        assert_eq!(sent, Some(16));
+        let mut out = [0u8; 32];
+        assert_eq!(read_some(&dst_r, &mut out), 16);
+        assert_eq!(&out[..16], &data[..]);
+
+        assert_eq!(io.recv(src_r.as_raw_fd()).unwrap(), 4);
+        drop(src_w);
+        assert_eq!(io.recv(src_r.as_raw_fd()).unwrap(), 0);
+        assert_eq!(io.send(dst_w.as_raw_fd()).unwrap(), 4);
+        assert_eq!(read_some(&dst_r, &mut out), 4);
+        assert_eq!(&out[..4], &data[..4]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "io_uring is not modelled")]
+    fn settled_side_is_reported_despite_the_other() {
+        let mut ring = RingBuf::new(2);
+        let mut io = match UringIo::new(ring.clone(), 8) {
+            Ok(io) => io,
+            Err(err) => {
+                eprintln!("skipping: io_uring unavailable: {}", err);
+                return;
+            }
+        };
+        let (src_r, src_w) = pipe();
+        let (dst_r, dst_w) = pipe();
+        ring.write(&[7u8; 8]).unwrap();
+
+        // reading the write end fails, but the send beside it is still consumed
+        assert!(io.queue_recv(src_w.as_raw_fd()).unwrap());
+        assert!(io.queue_send(dst_w.as_raw_fd()).unwrap());
+        assert!(io.complete(2).is_err());
+        assert_eq!(ring.available_data_size(), 0);
+        assert_eq!(
+            io.complete(1).unwrap(),
+            Completions {
+                received: None,
+                sent: Some(8),
+            }
+        );
+
+        // a receive that settles while send() waits is kept for complete()
+        write_all(&src_w, &[1, 2, 3, 4]);
+        ring.write(&[9u8; 5]).unwrap();
+        assert!(io.queue_recv(src_r.as_raw_fd()).unwrap());
+        assert_eq!(io.send(dst_w.as_raw_fd()).unwrap(), 5);
+        let mut received = None;
+        while received.is_none() {
+            received = io.complete(1).unwrap().received;
+        }
+        assert_eq!(received, Some(4));
+        assert_eq!(ring.available_data_size(), 4);
+        assert_eq!(read_some(&dst_r, &mut [0u8; 32]), 13);
+    }
+}
@@ -0,0 +1,119 @@
+#![allow(clippy::needless_return)]
+use crate::ring_buffer::{RingBuf, RingStats};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Background thread that watches a ring for progress. If neither `head` nor
+/// `tail` moves for `timeout`, `on_stall` is called once with a snapshot; it is
+/// re-armed as soon as either index moves again. Dropping the watchdog stops it.
+pub struct Watchdog {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn spawn<F>(ring: RingBuf, timeout: Duration, mut on_stall: F) -> Self
+    where
+        F: FnMut(&RingStats) + Send + 'static,
+    {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let poll = std::cmp::max(timeout / 4, Duration::from_millis(1));
+
+        let handle = thread::spawn(move || {
+            let (lock, cvar) = &*thread_stop;
+            let mut last = ring.stats();
+            let mut last_progress = Instant::now();
+            let mut armed = true;
+
+            let mut stopped = lock.lock().unwrap();
+            while !*stopped {
+                stopped = cvar.wait_timeout(stopped, poll).unwrap().0;
+
+                let now = ring.stats();
+                if now.head != last.head || now.tail != last.tail {
+                    last = now;
+                    last_progress = Instant::now();
+                    armed = true;
+                } else if armed && last_progress.elapsed() >= timeout {
+                    on_stall(&now);
+                    armed = false;
+                }
+            }
+        });
+
+        return Self {
+            stop,
+            handle: Some(handle),
+        };
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl RingBuf {
+    pub fn watchdog<F>(&self, timeout: Duration, on_stall: F) -> Watchdog
+    where
+        F: FnMut(&RingStats) + Send + 'static,
+    {
+        return Watchdog::spawn(self.clone(), timeout, on_stall);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn stalled_ring_reports_once_with_snapshot() {
+        let ring = RingBuf::new(2);
+        ring.produce(8);
+        let (tx, rx) = mpsc::channel();
+        let _watchdog = ring.watchdog(Duration::from_millis(20), move |stats| {
+            tx.send((stats.head, stats.tail)).unwrap();
+        });
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), (0, 8));
+        // still stalled, but it has already been reported
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn progress_rearms_on_stall() {
+        let ring = RingBuf::new(2);
+        let (tx, rx) = mpsc::channel();
+        let _watchdog = ring.watchdog(Duration::from_millis(20), move |stats| {
+            tx.send(stats.head).unwrap();
+        });
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+        ring.produce(8);
+        ring.consume(8);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 8);
+    }
+
+    #[test]
+    fn dropping_the_watchdog_stops_it() {
+        let ring = RingBuf::new(2);
+        let (tx, rx) = mpsc::channel();
+        let watchdog = ring.watchdog(Duration::from_secs(60), move |_| {
+            tx.send(()).unwrap();
+        });
+        drop(watchdog);
+        // the thread has been joined and its sender dropped, without a report
+        assert_eq!(rx.recv(), Err(mpsc::RecvError));
+    }
+}